@@ -0,0 +1,113 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+// A cgroup v2 for a single run of the child
+// Every process started by the child stays in it, even those that leave the process group
+pub struct Cgroup {
+    path: PathBuf,
+    procs: File,
+}
+
+impl Cgroup {
+    pub fn create(parent: &Path, name: &str) -> Result<Cgroup, Errno> {
+        let path = parent.join(name);
+        fs::create_dir(&path).map_err(errno)?;
+
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .map_err(errno)?;
+
+        Ok(Cgroup { path, procs })
+    }
+
+    // Writing "0" to this file descriptor moves the writing process into the cgroup
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    pub fn pids(&self) -> Result<Vec<Pid>, Errno> {
+        let procs = fs::read_to_string(self.path.join("cgroup.procs")).map_err(errno)?;
+
+        Ok(procs
+            .lines()
+            .filter_map(|line| line.parse().ok())
+            .map(Pid::from_raw)
+            .collect())
+    }
+
+    // Zombies are not counted, a cgroup with only unreaped processes is not populated
+    pub fn is_populated(&self) -> Result<bool, Errno> {
+        let events = fs::read_to_string(self.path.join("cgroup.events")).map_err(errno)?;
+
+        Ok(events.lines().any(|line| line == "populated 1"))
+    }
+
+    // Waits until no process is left in the cgroup, returns false if some are once time runs out
+    // A change of cgroup.events is reported by poll as POLLPRI, it is read again after each one
+    pub fn wait_empty(&self, timeout: Duration) -> Result<bool, Errno> {
+        let mut events = File::open(self.path.join("cgroup.events")).map_err(errno)?;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let mut contents = String::new();
+            events.seek(SeekFrom::Start(0)).map_err(errno)?;
+            events.read_to_string(&mut contents).map_err(errno)?;
+
+            if !contents.lines().any(|line| line == "populated 1") {
+                return Ok(true);
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(false);
+            }
+
+            let mut pfd = [PollFd::new(events.as_fd(), PollFlags::POLLPRI)];
+            match poll(
+                &mut pfd,
+                PollTimeout::try_from(left).unwrap_or(PollTimeout::MAX),
+            ) {
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sends SIGKILL to every process in the cgroup
+    pub fn kill(&self) -> Result<(), Errno> {
+        match fs::write(self.path.join("cgroup.kill"), "1") {
+            Ok(()) => Ok(()),
+            // cgroup.kill was added in Linux 5.14, kill the processes one by one instead
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                for pid in self.pids()? {
+                    match kill(pid, Signal::SIGKILL) {
+                        Ok(()) | Err(Errno::ESRCH) => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => Err(errno(e)),
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Only fails if processes are left in the cgroup, there is nothing more we can do then
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+fn errno(e: io::Error) -> Errno {
+    Errno::from_raw(e.raw_os_error().unwrap_or(0))
+}
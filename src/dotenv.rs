@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::Path;
+
+// Reads the variables of a dotenv file
+pub fn read(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+    parse(&contents).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+// Parses KEY=VALUE lines, with optional export prefixes, comments and quoted values
+// Single quoted values are taken literally, double quoted values may contain escapes
+// Quoted values may span several lines
+fn parse(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    let mut lines = contents.lines().enumerate();
+
+    while let Some((index, line)) = lines.next() {
+        let lineno = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = match line.strip_prefix("export") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+            _ => line,
+        };
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {lineno}: expected KEY=VALUE"));
+        };
+
+        let key = key.trim_end();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("line {lineno}: invalid key '{key}'"));
+        }
+
+        let value = match value.trim_start().chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let mut value = value.trim_start()[1..].to_string();
+
+                let end = loop {
+                    if let Some(end) = closing_quote(&value, quote) {
+                        break end;
+                    }
+
+                    let Some((_, next_line)) = lines.next() else {
+                        return Err(format!("line {lineno}: unterminated quoted value"));
+                    };
+
+                    value.push('\n');
+                    value.push_str(next_line);
+                };
+
+                let rest = value[end + 1..].trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(format!(
+                        "line {lineno}: unexpected '{rest}' after quoted value"
+                    ));
+                }
+
+                value.truncate(end);
+
+                if quote == '"' {
+                    unescape(&value)
+                } else {
+                    value
+                }
+            }
+            // Unquoted values end at a comment, which starts with whitespace and a #
+            _ => {
+                let end = value
+                    .match_indices('#')
+                    .map(|(i, _)| i)
+                    .find(|&i| value[..i].ends_with(char::is_whitespace))
+                    .unwrap_or(value.len());
+
+                value[..end].trim().to_string()
+            }
+        };
+
+        vars.push((key.to_string(), value));
+    }
+
+    Ok(vars)
+}
+
+// Finds the quote that ends a value, skipping escaped quotes within double quotes
+fn closing_quote(value: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote == '"' {
+            escaped = true;
+        } else if c == quote {
+            return Some(i);
+        }
+    }
+
+    None
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(c @ ('"' | '\\' | '$')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
@@ -1,34 +1,97 @@
-use std::os::fd::AsFd;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{cmp, process};
 
 use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
 use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
-use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
 use nix::sys::signal::Signal;
 use nix::sys::signal::Signal::{SIGCHLD, SIGHUP, SIGINT, SIGTERM};
 use nix::sys::signalfd::{SfdFlags, SignalFd};
-use nix::unistd::Pid;
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use nix::unistd::{self, Pid};
 
 use walkdir::WalkDir;
 
+use crate::notify::{Message, NotifySocket};
+use crate::output;
 use crate::parse_args::Options;
 
 const SIGNAL_EVENT: u64 = 1;
 const INOTIFY_EVENT: u64 = 2;
+const CHILD_EVENT: u64 = 3;
+const BUILD_EVENT: u64 = 4;
+const NOTIFY_EVENT: u64 = 5;
+const STDOUT_EVENT: u64 = 6;
+const STDERR_EVENT: u64 = 7;
+const PROBE_EVENT: u64 = 8;
+// Timers are told apart by adding the Timer to this
+const TIMER_EVENT: u64 = 16;
+
+// How much output is read from each pipe at once, so that a chatty child does not keep us from
+// other events
+const READ_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Timer {
+    // The current run of the child has taken too long
+    Timeout,
+    // The child should be run again
+    Every,
+    // Files have settled after a change, the build should be started
+    Build,
+    // The child did not send WATCHDOG=1 in time
+    Watchdog,
+    // The child has been silent for too long
+    Liveness,
+    // A health check is due, or the running one has taken too long
+    Health,
+    // The memory usage of the child should be checked
+    Memory,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Event {
-    Terminate,
+    // Carries the signal that terminated runar, if any
+    Terminate(Option<Signal>),
     FilesChanged,
-    ChildExit(Pid),
+    ChildExit,
+    BuildExit,
+    ProbeExit,
+    // The child sent sd_notify messages, they are read with read_notify
+    Notify,
+    Timer(Timer),
+    // The child wrote output, it is read with read_output
+    // It comes after the timers, a child that keeps writing would otherwise hold them off
+    Output,
     Nothing,
 }
 
 pub struct EventHandler {
     epoll: Epoll,
     inotify: Inotify,
+    watches: Vec<(WatchDescriptor, PathBuf)>,
+    log_file: Option<PathBuf>,
     signalfd: SignalFd,
+    pidfd: Option<OwnedFd>,
+    build_pidfd: Option<OwnedFd>,
+    probe_pidfd: Option<OwnedFd>,
+    notify: Option<NotifySocket>,
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+    timers: Vec<(Timer, TimerFd)>,
+    sigchld: bool,
 }
 
 impl EventHandler {
@@ -36,7 +99,7 @@ impl EventHandler {
         opts.sigmask.thread_block()?;
 
         let signalfd = SignalFd::with_flags(&opts.sigmask, SfdFlags::SFD_NONBLOCK)?;
-        let inotify = setup_inotify(opts);
+        let (inotify, watches) = setup_inotify(opts);
 
         let signal_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_EVENT);
         let inotify_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, INOTIFY_EVENT);
@@ -45,30 +108,245 @@ impl EventHandler {
         epoll.add(&signalfd, signal_ep_ev)?;
         epoll.add(&inotify, inotify_ep_ev)?;
 
+        let notify = match opts.notify {
+            true => {
+                let (uid, gid) = opts.owner();
+                Some(NotifySocket::bind("notify", uid, gid)?)
+            }
+            false => None,
+        };
+
+        if let Some(notify) = &notify {
+            let notify_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, NOTIFY_EVENT);
+            epoll.add(notify, notify_ep_ev)?;
+        }
+
         Ok(EventHandler {
             epoll,
             inotify,
+            watches,
+            log_file: opts.log_file.clone(),
             signalfd,
+            pidfd: None,
+            build_pidfd: None,
+            probe_pidfd: None,
+            notify,
+            stdout: None,
+            stderr: None,
+            timers: Vec::new(),
+            sigchld: false,
         })
     }
 
+    // Watches the child through a pidfd, its exit is reported as Event::ChildExit
+    pub fn watch_child(&mut self, pid: Pid) -> Result<(), Errno> {
+        self.unwatch_child()?;
+        self.pidfd = Some(self.watch(pid, CHILD_EVENT)?);
+
+        Ok(())
+    }
+
+    // Stops watching the child, the pidfd stays readable after the child has exited
+    pub fn unwatch_child(&mut self) -> Result<(), Errno> {
+        if let Some(pidfd) = self.pidfd.take() {
+            self.epoll.delete(&pidfd)?;
+        }
+
+        Ok(())
+    }
+
+    // Watches the build command, its exit is reported as Event::BuildExit
+    pub fn watch_build(&mut self, pid: Pid) -> Result<(), Errno> {
+        self.unwatch_build()?;
+        self.build_pidfd = Some(self.watch(pid, BUILD_EVENT)?);
+
+        Ok(())
+    }
+
+    pub fn unwatch_build(&mut self) -> Result<(), Errno> {
+        if let Some(pidfd) = self.build_pidfd.take() {
+            self.epoll.delete(&pidfd)?;
+        }
+
+        Ok(())
+    }
+
+    // Watches the health check command, its exit is reported as Event::ProbeExit
+    pub fn watch_probe(&mut self, pid: Pid) -> Result<(), Errno> {
+        self.unwatch_probe()?;
+        self.probe_pidfd = Some(self.watch(pid, PROBE_EVENT)?);
+
+        Ok(())
+    }
+
+    pub fn unwatch_probe(&mut self) -> Result<(), Errno> {
+        if let Some(pidfd) = self.probe_pidfd.take() {
+            self.epoll.delete(&pidfd)?;
+        }
+
+        Ok(())
+    }
+
+    fn watch(&self, pid: Pid, data: u64) -> Result<OwnedFd, Errno> {
+        let pidfd = pidfd_open(pid)?;
+        let pid_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, data);
+        self.epoll.add(&pidfd, pid_ep_ev)?;
+
+        Ok(pidfd)
+    }
+
+    // Watches the pipes of the child's output, output is reported as Event::Output
+    pub fn watch_output(&mut self, stdout: OwnedFd, stderr: OwnedFd) -> Result<(), Errno> {
+        self.unwatch_output()?;
+
+        for (pipe, data) in [(&stdout, STDOUT_EVENT), (&stderr, STDERR_EVENT)] {
+            fcntl(pipe.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+            let output_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, data);
+            self.epoll.add(pipe, output_ep_ev)?;
+        }
+
+        self.stdout = Some(stdout);
+        self.stderr = Some(stderr);
+
+        Ok(())
+    }
+
+    pub fn unwatch_output(&mut self) -> Result<(), Errno> {
+        for pipe in [self.stdout.take(), self.stderr.take()]
+            .into_iter()
+            .flatten()
+        {
+            self.epoll.delete(&pipe)?;
+        }
+
+        Ok(())
+    }
+
+    // Reads the output that is waiting in the pipes, up to READ_LIMIT of each, the rest is
+    // reported again. Closed pipes are no longer watched
+    pub fn read_output(&mut self) -> Result<Vec<(Stream, Vec<u8>)>, Errno> {
+        let mut output = Vec::new();
+        let mut buf = [0; 4096];
+
+        for (stream, pipe) in [
+            (Stream::Stdout, &mut self.stdout),
+            (Stream::Stderr, &mut self.stderr),
+        ] {
+            let mut read = 0;
+            while let Some(fd) = pipe.as_ref().filter(|_| read < READ_LIMIT) {
+                match unistd::read(fd.as_raw_fd(), &mut buf) {
+                    Ok(0) => {
+                        self.epoll.delete(fd)?;
+                        *pipe = None;
+                    }
+                    Ok(len) => {
+                        read += len;
+                        output.push((stream, buf[..len].to_vec()));
+                    }
+                    Err(Errno::EAGAIN) => break,
+                    Err(Errno::EINTR) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(output)
+    }
+
+    // The path that the child should send its sd_notify messages to, if --notify is used
+    pub fn notify_path(&self) -> Option<&Path> {
+        self.notify.as_ref().map(NotifySocket::path)
+    }
+
+    pub fn read_notify(&self) -> Vec<Message> {
+        match &self.notify {
+            Some(notify) => notify.recv(),
+            None => Vec::new(),
+        }
+    }
+
+    // Arms the timer, it is reported as Event::Timer once the duration has passed
+    pub fn set_timer(&mut self, timer: Timer, duration: Duration) -> Result<(), Errno> {
+        // A zero duration would disarm the timer instead
+        let duration = cmp::max(duration, Duration::from_nanos(1));
+        let expiration = Expiration::OneShot(TimeSpec::from_duration(duration));
+
+        self.timer(timer)?
+            .set(expiration, TimerSetTimeFlags::empty())
+    }
+
+    // Arms the timer, it is reported as Event::Timer every time the interval passes
+    pub fn set_interval(&mut self, timer: Timer, interval: Duration) -> Result<(), Errno> {
+        let expiration = Expiration::Interval(TimeSpec::from_duration(interval));
+
+        self.timer(timer)?
+            .set(expiration, TimerSetTimeFlags::empty())
+    }
+
+    // Disarms the timer, also dropping an expiration that has not been handled yet
+    pub fn unset_timer(&mut self, timer: Timer) -> Result<(), Errno> {
+        match self.timers.iter().find(|(t, _)| *t == timer) {
+            Some((_, timerfd)) => timerfd.unset(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn unset_timers(&mut self, timers: &[Timer]) -> Result<(), Errno> {
+        for timer in timers {
+            self.unset_timer(*timer)?;
+        }
+
+        Ok(())
+    }
+
+    // Timers are created and added to epoll the first time they are used
+    fn timer(&mut self, timer: Timer) -> Result<&TimerFd, Errno> {
+        let index = match self.timers.iter().position(|(t, _)| *t == timer) {
+            Some(index) => index,
+            None => {
+                let flags = TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC;
+                let timerfd = TimerFd::new(ClockId::CLOCK_MONOTONIC, flags)?;
+
+                let timer_ep_ev = EpollEvent::new(EpollFlags::EPOLLIN, TIMER_EVENT + timer as u64);
+                self.epoll.add(&timerfd, timer_ep_ev)?;
+
+                self.timers.push((timer, timerfd));
+                self.timers.len() - 1
+            }
+        };
+
+        Ok(&self.timers[index].1)
+    }
+
+    // Waits for signals while the child is stopped, output of the child is reported as well so
+    // that it can be read before the child blocks on a full pipe
     pub fn wait_signals(&mut self, timeout: PollTimeout) -> Result<Event, Errno> {
-        let mut pfd = [PollFd::new(self.signalfd.as_fd(), PollFlags::POLLIN)];
+        let mut pfds = vec![PollFd::new(self.signalfd.as_fd(), PollFlags::POLLIN)];
+        pfds.extend(
+            [&self.stdout, &self.stderr]
+                .into_iter()
+                .flatten()
+                .map(|pipe| PollFd::new(pipe.as_fd(), PollFlags::POLLIN)),
+        );
 
         // TODO: tryfrom
-        let res = poll(&mut pfd, timeout)?;
+        let res = poll(&mut pfds, timeout)?;
+        let signalled = pfds[0]
+            .revents()
+            .is_some_and(|revents| revents.contains(PollFlags::POLLIN));
 
-        if res.is_positive() {
-            Ok(read_signal(&self.signalfd))
-        } else {
-            Ok(Event::Nothing)
+        match (signalled, res.is_positive()) {
+            (true, _) => Ok(self.read_signals()),
+            (false, true) => Ok(Event::Output),
+            (false, false) => Ok(Event::Nothing),
         }
     }
 
-    pub fn wait(&mut self, timeout: i32) -> Result<Event, Errno> {
+    pub fn wait(&mut self, timeout: PollTimeout) -> Result<Event, Errno> {
         let mut ep_evs = [EpollEvent::empty(); 10];
 
-        let ready_fds = self.epoll.wait(&mut ep_evs, timeout as u16)?;
+        let ready_fds = self.epoll.wait(&mut ep_evs, timeout)?;
 
         let mut event = Event::Nothing;
 
@@ -77,65 +355,134 @@ impl EventHandler {
             let data = ev.data();
             let new_event;
             if data == SIGNAL_EVENT {
-                new_event = read_signal(&self.signalfd);
+                new_event = self.read_signals();
             } else if data == INOTIFY_EVENT {
-                self.clear_inotify()?;
+                if !self.read_inotify()? {
+                    continue;
+                }
 
                 // TODO write which files changed if verbose
                 new_event = Event::FilesChanged;
+            } else if data == CHILD_EVENT {
+                new_event = Event::ChildExit;
+            } else if data == BUILD_EVENT {
+                new_event = Event::BuildExit;
+            } else if data == PROBE_EVENT {
+                new_event = Event::ProbeExit;
+            } else if data == NOTIFY_EVENT {
+                new_event = Event::Notify;
+            } else if data == STDOUT_EVENT || data == STDERR_EVENT {
+                new_event = Event::Output;
+            } else if let Some(&(timer, _)) = self
+                .timers
+                .iter()
+                .find(|(t, _)| TIMER_EVENT + *t as u64 == data)
+            {
+                // The timer is read once the event is returned, until then it stays readable
+                new_event = Event::Timer(timer);
             } else {
                 eprintln!("<runar> epoll_wait returned unknown data");
                 // TODO return error
-                new_event = Event::Terminate;
+                new_event = Event::Terminate(None);
             }
 
             // We will return the highest priority event
             event = cmp::min(event, new_event);
         }
 
+        if let Event::Timer(timer) = event {
+            if let Some((_, timerfd)) = self.timers.iter().find(|(t, _)| *t == timer) {
+                match timerfd.wait() {
+                    Ok(()) | Err(Errno::EAGAIN) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
         Ok(event)
     }
 
-    pub fn clear_inotify(&mut self) -> Result<(), Errno> {
-        match self.inotify.read_events() {
-            Ok(_) => Ok(()),
-            Err(Errno::EAGAIN) => Ok(()), // No events pending
-            Err(e) => Err(e),
-        }
+    // Reads the pending inotify events, returns false if they were all about the log file
+    fn read_inotify(&mut self) -> Result<bool, Errno> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(true), // No events pending
+            Err(e) => return Err(e),
+        };
+
+        let changed = events.iter().any(|event| {
+            let Some((_, path)) = self.watches.iter().find(|(wd, _)| *wd == event.wd) else {
+                return true;
+            };
+
+            match &event.name {
+                Some(name) => !is_log_file(&path.join(name), self.log_file.as_deref()),
+                None => !is_log_file(path, self.log_file.as_deref()),
+            }
+        });
+
+        Ok(changed)
     }
 
-    // TODO close and drop functions
-}
+    // Returns true if any SIGCHLD was received since the last call
+    // The kernel merges pending SIGCHLDs, so one of them may stand for many exited children
+    pub fn take_sigchld(&mut self) -> bool {
+        std::mem::take(&mut self.sigchld)
+    }
+
+    // Reads all pending signals and returns the highest priority event
+    fn read_signals(&mut self) -> Event {
+        let mut event = Event::Nothing;
 
-fn read_signal(signalfd: &SignalFd) -> Event {
-    match signalfd.read_signal() {
-        Ok(Some(sig)) => {
-            let signal = Signal::try_from(sig.ssi_signo as i32).unwrap();
-
-            match signal {
-                SIGTERM | SIGINT | SIGHUP => Event::Terminate,
-                SIGCHLD => Event::ChildExit(Pid::from_raw(sig.ssi_pid as i32)),
-                _ => {
-                    eprintln!("<runar> Unexpected signal {signal} caught by signalfd");
-                    Event::Terminate
+        loop {
+            let new_event = match self.signalfd.read_signal() {
+                Ok(Some(sig)) => {
+                    let signal = Signal::try_from(sig.ssi_signo as i32).unwrap();
+
+                    match signal {
+                        SIGTERM | SIGINT | SIGHUP => Event::Terminate(Some(signal)),
+                        SIGCHLD => {
+                            self.sigchld = true;
+                            Event::Nothing
+                        }
+                        _ => {
+                            eprintln!("<runar> Unexpected signal {signal} caught by signalfd");
+                            Event::Terminate(None)
+                        }
+                    }
                 }
-            }
-        }
-        // there were no signals waiting (only happens when the SFD_NONBLOCK flag is set,
-        // otherwise the read_signal call blocks)
-        Ok(None) => Event::Nothing,
-        Err(e) => {
-            eprintln!("<runar> Error: {e}");
-            Event::Terminate
+                // there are no more signals waiting (only happens when the SFD_NONBLOCK flag is
+                // set, otherwise the read_signal call blocks)
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("<runar> Error: {e}");
+                    return Event::Terminate(None);
+                }
+            };
+
+            event = cmp::min(event, new_event);
         }
+
+        event
     }
+
+    // TODO close and drop functions
+}
+
+fn pidfd_open(pid: Pid) -> Result<OwnedFd, Errno> {
+    // nix does not wrap pidfd_open, the returned fd always has the close-on-exec flag set
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+
+    Errno::result(res).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
 }
 
 // Set up Inotify instance
 // TODO clean up error handling here
-fn setup_inotify(opts: &Options) -> Inotify {
+// Returns the watched paths with their watch descriptors, the paths are made canonical
+fn setup_inotify(opts: &Options) -> (Inotify, Vec<(WatchDescriptor, PathBuf)>) {
     let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
         .expect("Error while initializing inotify instance");
+    let mut watches = Vec::new();
 
     for file in &opts.files {
         if opts.recursive {
@@ -156,18 +503,29 @@ fn setup_inotify(opts: &Options) -> Inotify {
                         }
                     }
                     Ok(entry) => {
-                        let path = entry.into_path();
+                        let path = canonical(entry.into_path());
+
+                        if is_log_file(&path, opts.log_file.as_deref()) {
+                            continue;
+                        }
 
                         // TODO generalize error handling for inotify
-                        inotify
+                        let wd = inotify
                             .add_watch(&path, AddWatchFlags::IN_CLOSE_WRITE)
                             .expect("Could not add watch");
+                        watches.push((wd, path));
                     }
                 };
             }
         } else {
-            match inotify.add_watch(file.as_os_str(), AddWatchFlags::IN_CLOSE_WRITE) {
-                Ok(_) => (),
+            let path = canonical(PathBuf::from(file));
+
+            if is_log_file(&path, opts.log_file.as_deref()) {
+                continue;
+            }
+
+            match inotify.add_watch(&path, AddWatchFlags::IN_CLOSE_WRITE) {
+                Ok(wd) => watches.push((wd, path)),
                 Err(Errno::ENOENT) => {
                     eprintln!(
                         "<runar> No such file or directory: {}",
@@ -183,5 +541,14 @@ fn setup_inotify(opts: &Options) -> Inotify {
         }
     }
 
-    inotify
+    (inotify, watches)
+}
+
+fn canonical(path: PathBuf) -> PathBuf {
+    path.canonicalize().unwrap_or(path)
+}
+
+// The log file is written to with every line of output, it must not count as a change
+fn is_log_file(path: &Path, log_file: Option<&Path>) -> bool {
+    log_file.is_some_and(|log_file| output::is_log_file(path, log_file))
 }
@@ -1,18 +1,66 @@
+mod cgroup;
+mod dotenv;
 mod event_handler;
+mod notify;
+mod output;
 mod parse_args;
+mod proc;
+mod procfile;
 
+use std::cmp;
+use std::collections::VecDeque;
+use std::ffi::{CString, OsStr};
+use std::io::{self, IsTerminal};
+use std::os::fd::BorrowedFd;
 use std::os::unix::process::CommandExt;
-use std::process::{self, Command, ExitCode};
+use std::path::Path;
+use std::process::{self, Child, Command, ExitCode, Stdio};
+use std::time::{Duration, Instant};
 
 use nix::errno::Errno;
+use nix::libc;
 use nix::poll::PollTimeout;
+use nix::sched;
 use nix::sys::prctl;
-use nix::sys::signal::{kill, Signal};
+use nix::sys::resource;
+use nix::sys::signal::{kill, raise, SigSet, Signal};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{self, Pid};
 
-use event_handler::{Event, EventHandler};
-use parse_args::{parse_args, Options};
+use cgroup::Cgroup;
+use event_handler::{Event, EventHandler, Timer};
+use notify::Message;
+use output::{Action, Log, Output};
+use parse_args::{parse_args, Clear, Options, Overlap};
+
+// Time to wait before restarting, lets any residual changes to files settle
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+// Exit status of a run that was stopped by --timeout, the same as timeout(1)
+const TIMED_OUT: u8 = 124;
+
+// Exit statuses of a run that could not be started, the same as a shell gives
+const NOT_EXECUTABLE: u8 = 126;
+const NOT_FOUND: u8 = 127;
+
+// The timers that belong to a single run of the child
+const RUN_TIMERS: [Timer; 5] = [
+    Timer::Timeout,
+    Timer::Watchdog,
+    Timer::Liveness,
+    Timer::Health,
+    Timer::Memory,
+];
+
+// How often the memory usage of the child is checked against --max-rss
+const MEMORY_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the processes in a cgroup may take to be gone once they got SIGKILL
+const CGROUP_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Set to our pid for the build and the health check, their processes keep it when they leave
+// the process tree, so that they are not taken for escaped processes of the child
+const HELPER_VAR: &str = "RUNAR_HELPER";
 
 #[derive(Clone, Copy, Debug)]
 /// Child process state
@@ -33,8 +81,14 @@ fn main() -> ExitCode {
         println!("<runar> started with pid {}", process::id());
     }
 
-    let exitstatus = match run_loop(&opts) {
+    let exitstatus = match &opts.procfile {
+        Some(procfile) => procfile::run(procfile, &opts),
+        None => run_loop(&opts).map_err(io::Error::from),
+    };
+
+    let exitstatus = match exitstatus {
         Err(e) => {
+            output::end_lines();
             eprintln!("<runar> Error: {e}");
             1
         }
@@ -45,6 +99,18 @@ fn main() -> ExitCode {
 }
 
 fn run_loop(opts: &Options) -> Result<u8, Errno> {
+    // The log file is opened before anything runs, so that a bad path is told right away
+    let log = match &opts.log_file {
+        Some(path) => match Log::open(path) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                eprintln!("<runar> Error: {}: {e}", path.display());
+                return Ok(1);
+            }
+        },
+        None => None,
+    };
+
     // Become a subreaper, taking on the responsibiliy of handling orphaned processess
     prctl::set_child_subreaper(true)?;
 
@@ -53,165 +119,1259 @@ fn run_loop(opts: &Options) -> Result<u8, Errno> {
     // The exit status of previously run of the command
     let mut exitstatus = 0;
 
-    let mut child_pid = spawn_child(opts);
+    // The signal that terminated runar, if any
+    let mut terminated_by = None;
+
+    // Counts the runs of the child, the first run is 1
+    let mut generation = 0;
+
+    // The child is started by the loop, as if it was restarted
+    let mut run = Run::new(Pid::from_raw(0), None);
     let mut event;
-    let mut state = ChildState::Alive;
+    let mut state = ChildState::Restarting;
+
+    // The running build command, its status if it was reaped before its pidfd reported the exit,
+    // and whether files changed while it was running
+    let mut build = None;
+    let mut build_status = None;
+    let mut rebuild = false;
+
+    // The running health check, its status if it was reaped before its pidfd reported the exit,
+    // and the number of health checks of the current run that failed in a row
+    let mut probe = None;
+    let mut probe_status = None;
+    let mut probe_failures = 0;
+
+    // Why the child is being restarted, shown in the --clear banner
+    let mut restart_reason = "";
+
+    // Files changed during a run that was not interrupted, it is run again once it exits
+    let mut rerun = false;
+
+    if opts.postpone {
+        // Nothing is run until files change
+        state = ChildState::Dormant;
+    } else if let Some(build_cmd) = &opts.build {
+        // The child is only started once it has been built
+        build = start_build(build_cmd, &mut handler, opts)?;
+        state = ChildState::Dormant;
+    }
+
+    if let Some(every) = opts.every {
+        handler.set_interval(Timer::Every, every)?;
+    }
+
+    // When the child should be restarted
+    let mut restart_at = Instant::now();
+
+    // The restarts after the child exited or failed, for --backoff and --max-restarts
+    let mut restarts = Restarts::new(opts);
+
+    // The lines of output that the current run is writing
+    let mut output = Output::new(log);
 
     loop {
         // TODO: the ultimate goal is to get rid of the need for tracking ChildState,
-        //       Could be possible using pidfd
-        //       Or by using kill with signal 0 to check process state
+        //       Could be possible by using kill with signal 0 to check process state
         event = match state {
             ChildState::Restarting => {
-                // Wait 100ms and then clear inotify of any residual changes to files
-                // We do not care about changed files here since we are restarting anyway
-                let res = handler.wait_signals(PollTimeout::from(100_u16))?;
-                handler.clear_inotify()?;
-                res
+                let delay = restart_at.saturating_duration_since(Instant::now());
+                handler.wait(PollTimeout::try_from(delay).unwrap_or(PollTimeout::MAX))?
             }
-            ChildState::Alive | ChildState::Dormant => handler.wait(-1)?,
+            ChildState::Alive | ChildState::Dormant => handler.wait(PollTimeout::NONE)?,
         };
 
         // TODO debug level
         if opts.verbose {
+            output::end_lines();
             println!("<runar> main loop state & event ({state:?}, {event:?})");
         }
 
+        // The build and the health check are not part of the child, they are spared when it is
+        // stopped
+        let spared: Vec<Pid> = build.into_iter().chain(probe).collect();
+
+        // Some children died, this cleans them up
+        // The child itself is handled when its pidfd reports the exit
+        if handler.take_sigchld() {
+            let watched: Vec<Pid> = spared.iter().copied().chain([run.pid]).collect();
+
+            for status in reap_children(&watched, opts)? {
+                if status.pid() == build {
+                    build_status = Some(status);
+                } else if status.pid() == probe {
+                    probe_status = Some(status);
+                } else {
+                    run.reaped_status = Some(status);
+                }
+            }
+        }
+
+        // Set by the checks that fail a running child, it is stopped and restarted for this reason
+        let mut restart_for = None;
+
         match (event, state) {
-            (Event::Terminate, ChildState::Alive) => {
-                term_wait_kill(child_pid, &mut handler, opts);
+            (Event::Terminate(signal), ChildState::Alive) => {
+                exitstatus = run
+                    .stop(&spared, &mut handler, &mut output, opts)?
+                    .unwrap_or(exitstatus);
+
+                terminated_by = signal;
                 break;
             }
-            (Event::Terminate, ChildState::Restarting | ChildState::Dormant) => {
+            (Event::Terminate(signal), ChildState::Restarting | ChildState::Dormant) => {
+                terminated_by = signal;
                 break;
             }
-            (Event::FilesChanged, ChildState::Alive) => {
-                term_wait_kill(child_pid, &mut handler, opts);
-                state = ChildState::Restarting; // Restart child
+            (Event::FilesChanged, _) if opts.build.is_some() => {
+                if build.is_some() {
+                    // The running build may have missed the change, build again once it is done
+                    rebuild = true;
+                } else {
+                    // Let the changes settle before building
+                    handler.set_timer(Timer::Build, RESTART_DELAY)?;
+                }
             }
-            (Event::FilesChanged, ChildState::Dormant) => {
-                state = ChildState::Restarting;
+            (Event::Timer(Timer::Build), _) => {
+                if let Some(build_cmd) = &opts.build {
+                    build = start_build(build_cmd, &mut handler, opts)?;
+                    build_status = None;
+                }
             }
-            (Event::ChildExit(dead_pid), ChildState::Alive) if dead_pid == child_pid => {
-                let child_status = waitpid(child_pid, Some(WaitPidFlag::WNOHANG))?;
-                exitstatus = match child_status {
-                    WaitStatus::Exited(_, status) => status as u8,
-                    WaitStatus::Signaled(_, signal, _) => 128 + signal as u8,
-                    status => {
+            (Event::BuildExit, _) => {
+                handler.unwatch_build()?;
+
+                let Some(build_pid) = build.take() else {
+                    continue;
+                };
+                let status = match build_status.take() {
+                    Some(status) => status,
+                    None => waitpid(build_pid, None)?,
+                };
+
+                // Anything the build left behind in its process group is not needed anymore
+                let _ = kill(Pid::from_raw(-build_pid.as_raw()), Signal::SIGKILL);
+
+                if rebuild {
+                    rebuild = false;
+                    build = start_build(opts.build.as_ref().unwrap(), &mut handler, opts)?;
+                    continue;
+                }
+
+                match exit_code(status) {
+                    Some(0) if opts.no_interrupt && matches!(state, ChildState::Alive) => {
+                        rerun = true;
+                    }
+                    Some(0) => {
+                        if let ChildState::Alive = state {
+                            exitstatus = run
+                                .stop(&spared, &mut handler, &mut output, opts)?
+                                .unwrap_or(exitstatus);
+                        }
+
+                        state = ChildState::Restarting;
+                        restart_reason = "build succeeded";
+                        restart_at = Instant::now();
+                    }
+                    // The current run of the child, if any, is kept
+                    Some(code) => {
+                        output::end_lines();
+                        eprintln!("<runar> build failed with status {code}");
+                    }
+                    None => {
+                        output::end_lines();
                         eprintln!("<runar> Error: Unhandled status {status:?}");
+                    }
+                }
+            }
+            (Event::Timer(Timer::Health), ChildState::Alive) if probe.is_none() => {
+                if let Some(health_cmd) = &opts.health_cmd {
+                    probe = start_probe(health_cmd, &mut handler, opts)?;
+                    probe_status = None;
+
+                    // The health check fails when it is still running once the interval passes
+                    handler.set_timer(Timer::Health, opts.health_interval)?;
+                }
+            }
+            (Event::ProbeExit | Event::Timer(Timer::Health), ChildState::Alive) => {
+                handler.unwatch_probe()?;
+
+                let Some(probe_pid) = probe.take() else {
+                    continue;
+                };
+
+                let healthy = if event == Event::ProbeExit {
+                    let status = match probe_status.take() {
+                        Some(status) => status,
+                        None => waitpid(probe_pid, None)?,
+                    };
+                    stop_group(probe_pid);
+
+                    match exit_code(status) {
+                        Some(0) => true,
+                        Some(code) => {
+                            output::end_lines();
+                            eprintln!("<runar> health check failed with status {code}");
+                            false
+                        }
+                        None => {
+                            output::end_lines();
+                            eprintln!("<runar> Error: Unhandled status {status:?}");
+                            false
+                        }
+                    }
+                } else {
+                    stop_group(probe_pid);
+                    output::end_lines();
+                    eprintln!(
+                        "<runar> health check timed out after {:?}",
+                        opts.health_interval
+                    );
+                    false
+                };
+
+                probe_failures = match healthy {
+                    true => 0,
+                    false => probe_failures + 1,
+                };
+
+                if probe_failures < opts.health_retries {
+                    handler.set_timer(Timer::Health, opts.health_interval)?;
+                    continue;
+                }
+
+                output::end_lines();
+                eprintln!(
+                    "<runar> {} failed {probe_failures} health checks in a row, restarting",
+                    opts.command[0].to_string_lossy()
+                );
+
+                restart_for = Some("health checks failed");
+            }
+            (Event::Timer(Timer::Memory), ChildState::Alive) => {
+                let Some(max_rss) = opts.max_rss else {
+                    continue;
+                };
+
+                // Processes that left the process group are only counted with a cgroup
+                let rss = match &run.cgroup {
+                    Some(cgroup) => cgroup
+                        .pids()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(proc::stat)
+                        .map(|stat| stat.rss)
+                        .sum(),
+                    None => proc::group_rss(run.pid),
+                };
+
+                if rss <= max_rss {
+                    continue;
+                }
+
+                output::end_lines();
+                eprintln!(
+                    "<runar> {} uses {} of memory, more than {}, restarting",
+                    opts.command[0].to_string_lossy(),
+                    format_size(rss),
+                    format_size(max_rss)
+                );
+
+                restart_for = Some("over --max-rss");
+            }
+            (Event::ProbeExit, _) => {
+                // The run that was checked is gone, the result does not matter anymore
+                handler.unwatch_probe()?;
+                if let Some(probe_pid) = probe.take() {
+                    stop_group(probe_pid);
+                }
+            }
+            (Event::Notify, _) => {
+                for message in handler.read_notify() {
+                    // Messages from a run that has been stopped are of no interest
+                    if !matches!(state, ChildState::Alive) {
                         continue;
                     }
+
+                    match &message {
+                        Message::Ready => run.ready = true,
+                        Message::Reloading => run.ready = false,
+                        Message::Watchdog => {
+                            if let Some(watchdog) = opts.watchdog {
+                                handler.set_timer(Timer::Watchdog, watchdog)?;
+                            }
+                            if let Some(liveness) = opts.liveness {
+                                handler.set_timer(Timer::Liveness, liveness)?;
+                            }
+                        }
+                        Message::Stopping | Message::Restarted => (),
+                        Message::Status(status) => {
+                            if opts.verbose {
+                                output::end_lines();
+                                println!("<runar> child process status: {status}");
+                            }
+                        }
+                    }
+
+                    // The service manager that runar runs under is told how the child is doing
+                    if matches!(
+                        message,
+                        Message::Ready | Message::Reloading | Message::Status(_)
+                    ) {
+                        let _ = notify::send(&message);
+                    }
+                }
+            }
+            (Event::Output, _) => {
+                let action = forward_output(&mut handler, &mut output, opts)?;
+
+                // Output from a run that has been stopped says nothing about the current one
+                if !matches!(state, ChildState::Alive) {
+                    continue;
+                }
+
+                if let Some(liveness) = opts.liveness {
+                    handler.set_timer(Timer::Liveness, liveness)?;
+                }
+
+                let Some(action) = action else {
+                    continue;
                 };
 
-                // Kill all children in pgrp
-                term_wait_kill(child_pid, &mut handler, opts);
+                print_output_action(action, opts);
+
+                if action == Action::Exit {
+                    exitstatus = run
+                        .stop(&spared, &mut handler, &mut output, opts)?
+                        .unwrap_or(exitstatus);
+                    break;
+                }
 
+                restart_for = Some("output matched a pattern");
+            }
+            (Event::FilesChanged, ChildState::Alive) if opts.no_interrupt => {
                 if opts.verbose {
+                    output::end_lines();
+                    println!("<runar> files changed, rerunning once the child process exits");
+                }
+
+                rerun = true;
+            }
+            (Event::FilesChanged, ChildState::Alive) => {
+                exitstatus = run
+                    .stop(&spared, &mut handler, &mut output, opts)?
+                    .unwrap_or(exitstatus);
+
+                state = ChildState::Restarting; // Restart child
+                restart_reason = "files changed";
+                restart_at = Instant::now() + RESTART_DELAY;
+            }
+            (Event::FilesChanged, ChildState::Dormant) => {
+                state = ChildState::Restarting;
+                restart_reason = "files changed";
+                restart_at = Instant::now() + RESTART_DELAY;
+            }
+            (Event::FilesChanged, ChildState::Restarting) => {
+                // We are restarting anyway, but should not wait longer than for a file change
+                restart_at = cmp::min(restart_at, Instant::now() + RESTART_DELAY);
+            }
+            (Event::Timer(Timer::Liveness), ChildState::Alive) => {
+                output::end_lines();
+                eprintln!(
+                    "<runar> {} produced no output for {:?}, restarting",
+                    opts.command[0].to_string_lossy(),
+                    opts.liveness.unwrap_or_default()
+                );
+
+                restart_for = Some("no output");
+            }
+            (
+                Event::ChildExit | Event::Timer(Timer::Timeout | Timer::Watchdog),
+                ChildState::Alive,
+            ) => {
+                if event == Event::Timer(Timer::Timeout) {
+                    output::end_lines();
+                    eprintln!(
+                        "<runar> {} timed out after {:?}",
+                        opts.command[0].to_string_lossy(),
+                        opts.timeout.unwrap_or_default()
+                    );
+
+                    run.stop(&spared, &mut handler, &mut output, opts)?;
+                    exitstatus = TIMED_OUT;
+                } else if event == Event::Timer(Timer::Watchdog) {
+                    output::end_lines();
+                    eprintln!(
+                        "<runar> {} did not send WATCHDOG=1 within {:?}",
+                        opts.command[0].to_string_lossy(),
+                        opts.watchdog.unwrap_or_default()
+                    );
+
+                    exitstatus = run
+                        .stop(&spared, &mut handler, &mut output, opts)?
+                        .unwrap_or(1);
+                } else {
+                    let child_status = match run.reaped_status {
+                        Some(status) => status,
+                        None => waitpid(run.pid, None)?,
+                    };
+                    run.reaped_status = Some(child_status);
+
+                    // Kill all children in pgrp
+                    exitstatus = match run.stop(&spared, &mut handler, &mut output, opts)? {
+                        Some(status) => status,
+                        None => {
+                            output::end_lines();
+                            eprintln!("<runar> Error: Unhandled status {child_status:?}");
+                            continue;
+                        }
+                    };
+                }
+
+                // The last output of the run comes before anything we say about its exit
+                let action = forward_output(&mut handler, &mut output, opts)?;
+                output.finish_run();
+
+                if opts.verbose {
+                    output::end_lines();
                     println!("<runar> child process exited with {exitstatus}");
                 }
 
-                if opts.exit_on_zero && exitstatus == 0 {
-                    break;
+                if let Some(action) = action {
+                    print_output_action(action, opts);
                 }
 
-                if opts.exit_on_error && exitstatus != 0 {
+                if action == Some(Action::Exit) {
                     break;
                 }
 
-                if opts.restart_on_zero && exitstatus == 0 {
-                    // Next loop we will wait a bit to prevent the child restart loop from spazzing out
-                    state = ChildState::Restarting;
-                } else if opts.restart_on_error && exitstatus != 0 {
-                    state = ChildState::Restarting;
-                } else {
-                    state = ChildState::Dormant;
+                let restart = action == Some(Action::Restart);
+                let healthy = run.healthy(opts);
+                let delay = match restarts.after_exit(exitstatus, restart, rerun, healthy, opts) {
+                    AfterExit::Exit => break,
+                    AfterExit::Wait => {
+                        state = ChildState::Dormant;
+                        continue;
+                    }
+                    AfterExit::Restart(delay) => delay,
+                };
+
+                state = ChildState::Restarting;
+                restart_reason = match (action, event) {
+                    (Some(Action::Restart), _) => "output matched a pattern",
+                    _ if rerun => "files changed",
+                    (_, Event::Timer(Timer::Timeout)) => "timed out",
+                    (_, Event::Timer(Timer::Watchdog)) => "missed the watchdog",
+                    _ => "restarted after exit",
+                };
+                restart_at = Instant::now() + delay;
+                rerun = false;
+            }
+            (Event::Timer(Timer::Every), ChildState::Alive) if opts.overlap == Overlap::Restart => {
+                exitstatus = run
+                    .stop(&spared, &mut handler, &mut output, opts)?
+                    .unwrap_or(exitstatus);
+
+                state = ChildState::Restarting;
+                restart_reason = "--every interval";
+                restart_at = Instant::now();
+            }
+            (Event::Timer(Timer::Every), ChildState::Alive) => {
+                if opts.verbose {
+                    output::end_lines();
+                    println!("<runar> child process is still running, skipping this run");
                 }
             }
-            (Event::ChildExit(dead_pid), _) => {
-                // Some inherited child died, this cleans it up
-                match waitpid(dead_pid, Some(WaitPidFlag::WNOHANG)) {
-                    Ok(_) => (),
-                    Err(Errno::ECHILD) => (), // Child was waited on before we handled the signal
-                    Err(e) => return Err(e),
-                };
+            (Event::Timer(Timer::Every), ChildState::Dormant) => {
+                state = ChildState::Restarting;
+                restart_reason = "--every interval";
+                restart_at = Instant::now();
             }
-            (Event::Nothing, ChildState::Alive | ChildState::Dormant) => (),
+            (
+                Event::ChildExit | Event::Timer(_) | Event::Nothing,
+                ChildState::Alive | ChildState::Dormant,
+            ) => (),
+            (_, ChildState::Restarting) if Instant::now() < restart_at => (),
             // restart process?
             (_, ChildState::Restarting) => {
                 // should needs to know if we should restart or not
                 // file change is always a restart condition,
                 // exit status is not
                 // should also take into account if the exit status was voluntary or not
-                child_pid = spawn_child(opts);
+
+                // Whatever the previous run left in its pipes is shown before the new run starts
+                forward_output(&mut handler, &mut output, opts)?;
+                output.finish_run();
+
+                // A health check of the previous run would be taken for one of the new run
+                handler.unwatch_probe()?;
+                if let Some(probe_pid) = probe.take() {
+                    stop_group(probe_pid);
+                }
+
+                generation += 1;
+                output.start_run(generation);
+
+                // Every run after the first starts on a clean screen
+                if let Some(clear) = opts.clear.filter(|_| generation > 1) {
+                    print_banner(clear, generation, restart_reason, exitstatus);
+                }
+
+                let cgroup = create_cgroup(opts, generation)?;
+                let mut child = match spawn_child(opts, cgroup.as_ref(), handler.notify_path()) {
+                    Ok(child) => child,
+                    Err(e) => {
+                        output::end_lines();
+                        eprintln!(
+                            "<runar> Error: could not run {}: {e}",
+                            opts.command[0].to_string_lossy()
+                        );
+
+                        // A failed spawn is a failed run, with the exit status a shell would give
+                        exitstatus = match e.kind() {
+                            io::ErrorKind::NotFound => NOT_FOUND,
+                            _ => NOT_EXECUTABLE,
+                        };
+
+                        match restarts.after_exit(exitstatus, false, false, false, opts) {
+                            AfterExit::Exit => break,
+                            AfterExit::Wait => state = ChildState::Dormant,
+                            AfterExit::Restart(delay) => {
+                                restart_reason = "could not run";
+                                restart_at = Instant::now() + delay;
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                let child_pid = Pid::from_raw(child.id() as i32);
+                if let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) {
+                    handler.watch_output(stdout.into(), stderr.into())?;
+                }
+
+                if opts.verbose {
+                    output::end_lines();
+                    println!("<runar> child process spawned with pid {child_pid}");
+                }
+
+                // Under a Procfile, the processes that come after ours are restarted after it
+                if generation > 1 {
+                    let _ = notify::send(&Message::Restarted);
+                }
+
+                handler.watch_child(child_pid)?;
+                run = Run::new(child_pid, cgroup);
+                if let Some(timeout) = opts.timeout {
+                    handler.set_timer(Timer::Timeout, timeout)?;
+                }
+                if let Some(watchdog) = opts.watchdog {
+                    handler.set_timer(Timer::Watchdog, watchdog)?;
+                }
+                if let Some(liveness) = opts.liveness {
+                    handler.set_timer(Timer::Liveness, liveness)?;
+                }
+                if opts.health_cmd.is_some() {
+                    handler.set_timer(Timer::Health, opts.health_interval)?;
+                }
+                if opts.max_rss.is_some() {
+                    handler.set_interval(Timer::Memory, MEMORY_INTERVAL)?;
+                }
+                probe_failures = 0;
                 state = ChildState::Alive;
             }
         }
+
+        // Restarts for a failed check count against --backoff and --max-restarts like exits do
+        if let Some(reason) = restart_for {
+            exitstatus = run
+                .stop(&spared, &mut handler, &mut output, opts)?
+                .unwrap_or(exitstatus);
+
+            let Some(delay) = restarts.delay(exitstatus, run.healthy(opts), opts) else {
+                break;
+            };
+
+            state = ChildState::Restarting;
+            restart_reason = reason;
+            restart_at = Instant::now() + delay;
+        }
     }
 
-    // TODO Always return same exitstatus as child
+    forward_output(&mut handler, &mut output, opts)?;
+    output.finish_run();
+
+    // A build or health check that is still running is of no use anymore
+    for pid in build.into_iter().chain(probe) {
+        stop_group(pid);
+    }
+
+    if let (true, Some(signal)) = (opts.reraise, terminated_by) {
+        if opts.verbose {
+            output::end_lines();
+            println!("<runar> re-raising {signal}");
+        }
+
+        // The signal kills us before anything else is dropped, such as the notify socket
+        drop(handler);
+        drop(run);
+
+        // The signal is blocked for the signalfd, it is delivered as soon as it is unblocked
+        raise(signal)?;
+        let mut sigset = SigSet::empty();
+        sigset.add(signal);
+        sigset.thread_unblock()?;
+    }
 
     Ok(exitstatus)
 }
 
-fn spawn_child(opts: &Options) -> Pid {
+// A run of the child, from its spawn until it is stopped
+struct Run {
+    pid: Pid,
+    cgroup: Option<Cgroup>,
+    // Status of the child if it was reaped before its pidfd reported the exit
+    reaped_status: Option<WaitStatus>,
+    spawned_at: Instant,
+    // Whether the run sent READY=1, with --notify only ready runs count as healthy
+    ready: bool,
+}
+
+impl Run {
+    fn new(pid: Pid, cgroup: Option<Cgroup>) -> Run {
+        Run {
+            pid,
+            cgroup,
+            reaped_status: None,
+            spawned_at: Instant::now(),
+            ready: false,
+        }
+    }
+
+    // Stops the run along with everything it started, returns its exit status if it is known
+    // Processes in the spared process groups, such as a running build, are left alone
+    fn stop(
+        &mut self,
+        spared: &[Pid],
+        handler: &mut EventHandler,
+        output: &mut Output,
+        opts: &Options,
+    ) -> Result<Option<u8>, Errno> {
+        handler.unwatch_child()?;
+        handler.unset_timers(&RUN_TIMERS)?;
+
+        let cgroup = self.cgroup.take();
+        let child_status = term_wait_kill(self.pid, cgroup, spared, handler, output, opts);
+
+        Ok(child_status.or(self.reaped_status).and_then(exit_code))
+    }
+
+    // Whether the run was up long enough to reset --backoff, with --notify whether it was ready
+    fn healthy(&self, opts: &Options) -> bool {
+        match opts.notify {
+            true => self.ready,
+            false => self.spawned_at.elapsed() >= opts.healthy_uptime,
+        }
+    }
+}
+
+// What becomes of the child once a run of it exited
+enum AfterExit {
+    Exit,
+    // Nothing is run until files change
+    Wait,
+    Restart(Duration),
+}
+
+// The restarts of the child after it exited or failed, which are spread out by --backoff and
+// limited by --max-restarts
+struct Restarts {
+    // Times and exit statuses of the restarts within the --within window
+    recent: VecDeque<(Instant, u8)>,
+    // The delay before the next restart, when backing off
+    backoff_delay: Duration,
+}
+
+impl Restarts {
+    fn new(opts: &Options) -> Restarts {
+        Restarts {
+            recent: VecDeque::new(),
+            backoff_delay: opts.backoff.as_ref().map_or(RESTART_DELAY, |b| b.initial),
+        }
+    }
+
+    // Decides what happens once a run of the child exited, following -e, -E, -s and -S
+    // A run that did not see the latest changes is run again whatever its status, which does
+    // not count as a restart
+    fn after_exit(
+        &mut self,
+        exitstatus: u8,
+        restart: bool,
+        rerun: bool,
+        healthy: bool,
+        opts: &Options,
+    ) -> AfterExit {
+        if opts.exit_on_zero && exitstatus == 0 {
+            return AfterExit::Exit;
+        }
+
+        if opts.exit_on_error && exitstatus != 0 {
+            return AfterExit::Exit;
+        }
+
+        let restart = restart
+            || (opts.restart_on_zero && exitstatus == 0)
+            || (opts.restart_on_error && exitstatus != 0);
+
+        if restart {
+            match self.delay(exitstatus, healthy, opts) {
+                Some(delay) => AfterExit::Restart(delay),
+                None => AfterExit::Exit,
+            }
+        } else if rerun {
+            AfterExit::Restart(RESTART_DELAY)
+        } else {
+            AfterExit::Wait
+        }
+    }
+
+    // Returns the delay before the child is restarted, or None once it is given up on
+    fn delay(&mut self, exitstatus: u8, healthy: bool, opts: &Options) -> Option<Duration> {
+        if let Some(max_restarts) = opts.max_restarts {
+            self.recent
+                .retain(|(restarted_at, _)| restarted_at.elapsed() < opts.within);
+
+            if self.recent.len() >= max_restarts {
+                print_crash_loop(&self.recent, exitstatus, opts);
+                return None;
+            }
+
+            self.recent.push_back((Instant::now(), exitstatus));
+        }
+
+        // We will wait a bit to prevent the child restart loop from spazzing out
+        let delay = match &opts.backoff {
+            Some(backoff) => {
+                // The child was healthy, start backing off from scratch
+                if healthy {
+                    self.backoff_delay = backoff.initial;
+                }
+
+                let delay = self.backoff_delay;
+                // A delay too large for a Duration is capped like any other
+                self.backoff_delay =
+                    Duration::try_from_secs_f64(delay.as_secs_f64() * backoff.factor)
+                        .map_or(backoff.max, |next| cmp::min(next, backoff.max));
+                delay
+            }
+            None => RESTART_DELAY,
+        };
+
+        if opts.verbose {
+            output::end_lines();
+            println!("<runar> restarting child process in {delay:?}");
+        }
+
+        Some(delay)
+    }
+}
+
+// Exit status of a child, following the shell convention of 128 + signal for killed children
+fn exit_code(status: WaitStatus) -> Option<u8> {
+    match status {
+        WaitStatus::Exited(_, status) => Some(status as u8),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as u8),
+        _ => None,
+    }
+}
+
+// Formats a size in bytes with the largest unit that fits, the units are those of --max-rss
+fn format_size(size: u64) -> String {
+    let mut value = size as f64;
+    let mut units = ["K", "M", "G", "T"].iter();
+    let mut unit = "";
+
+    while value >= 1024.0 {
+        match units.next() {
+            Some(next) => unit = next,
+            None => break,
+        }
+        value /= 1024.0;
+    }
+
+    match unit {
+        "" => format!("{size}"),
+        unit => format!("{value:.1}{unit}"),
+    }
+}
+
+// Creates a cgroup for a run of the child, if cgroups are used
+fn create_cgroup(opts: &Options, generation: u32) -> Result<Option<Cgroup>, Errno> {
+    let Some(parent) = &opts.cgroup else {
+        return Ok(None);
+    };
+
+    let name = format!("runar-{}-{generation}", process::id());
+
+    Cgroup::create(parent, &name).map(Some)
+}
+
+// Starts a run of the child, which is reaped with waitpid once its pidfd reports the exit
+fn spawn_child(
+    opts: &Options,
+    cgroup: Option<&Cgroup>,
+    notify_path: Option<&Path>,
+) -> io::Result<Child> {
     let mut command = Command::new(&opts.command[0]);
     command.args(&opts.command[1..]);
     let sigmask = opts.sigmask;
+    let cgroup_procs = cgroup.map(Cgroup::procs_fd);
+
+    if let Some(dir) = &opts.chdir {
+        command.current_dir(dir);
+    }
+
+    if opts.env_clear {
+        command.env_clear();
+    }
+
+    if let Some(user) = &opts.user {
+        command.env("HOME", &user.dir);
+        command.env("USER", &user.name);
+        command.env("LOGNAME", &user.name);
+    }
+
+    // The env file is read on every spawn, so that changes to it are picked up
+    if let Some(env_file) = &opts.env_file {
+        match dotenv::read(env_file) {
+            Ok(vars) => {
+                command.envs(vars);
+            }
+            Err(e) => {
+                output::end_lines();
+                eprintln!("<runar> Error: {e}");
+            }
+        }
+    }
+
+    command.envs(opts.env.iter().map(|(key, value)| (key, value)));
+
+    if let Some(path) = notify_path {
+        command.env("NOTIFY_SOCKET", path);
+    }
+
+    if opts.capture_output() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    if let Some(watchdog) = opts.watchdog {
+        command.env("WATCHDOG_USEC", watchdog.as_micros().to_string());
+    }
+
+    let rlimits = opts.rlimits.clone();
+    let nice = opts.nice;
+    let cpu_affinity = opts.cpu_affinity;
+
+    // The identity is changed last before exec, we need our privileges until then
+    let (uid, gid) = opts.owner();
+    let groups = match (&opts.user, gid) {
+        (Some(user), Some(gid)) => CString::new(user.name.as_str())
+            .ok()
+            .and_then(|name| unistd::getgrouplist(&name, gid).ok())
+            .unwrap_or_else(|| vec![gid]),
+        (None, Some(gid)) => vec![gid],
+        (_, None) => Vec::new(),
+    };
 
     unsafe {
         command.pre_exec(move || {
             // the child inherits blocked signals, we must unblock them
-            sigmask.thread_unblock().unwrap();
+            sigmask.thread_unblock()?;
 
             // set a process group for the child, so we may easily kill the entire group
             // this group is inherited by all grandchildren (unless they change the group
             // themselves)
             // there is a process_group() function in rust 1.64 that could be used instead
-            unistd::setpgid(Pid::from_raw(0), Pid::from_raw(process::id() as i32)).unwrap();
+            unistd::setpgid(Pid::from_raw(0), Pid::from_raw(process::id() as i32))?;
+
+            // move into the cgroup before exec, so that every process the child starts ends
+            // up in it
+            if let Some(fd) = cgroup_procs {
+                unistd::write(BorrowedFd::borrow_raw(fd), b"0")?;
+            }
+
+            for rlimit in &rlimits {
+                resource::setrlimit(rlimit.resource, rlimit.soft, rlimit.hard)?;
+            }
+
+            if let Some(nice) = nice {
+                Errno::result(libc::setpriority(libc::PRIO_PROCESS, 0, nice))?;
+            }
+
+            if let Some(cpu_set) = &cpu_affinity {
+                sched::sched_setaffinity(Pid::from_raw(0), cpu_set)?;
+            }
+
+            if let Some(gid) = gid {
+                unistd::setgroups(&groups)?;
+                unistd::setgid(gid)?;
+            }
+
+            if let Some(uid) = uid {
+                unistd::setuid(uid)?;
+            }
 
             Ok(())
         });
     }
 
-    let child_pid = command.spawn().expect("Could not execute command").id() as i32;
+    command.spawn()
+}
+
+// Passes what the child wrote on to our own stdout and stderr, returns the action of the first
+// line that matched a pattern
+fn forward_output(
+    handler: &mut EventHandler,
+    output: &mut Output,
+    opts: &Options,
+) -> Result<Option<Action>, Errno> {
+    let mut action = None;
+
+    for (stream, data) in handler.read_output()? {
+        action = action.or(output.forward(stream, &data, opts));
+    }
+
+    Ok(action)
+}
+
+// Clears the terminal and tells why a new run of the child starts
+// Only the banner is written when stdout is not a terminal, such as a file or a pipe
+fn print_banner(clear: Clear, generation: u32, reason: &str, exitstatus: u8) {
+    output::end_lines();
+
+    match clear {
+        _ if !io::stdout().is_terminal() => (),
+        Clear::Screen => print!("\x1b[H\x1b[2J"),
+        // Also clears the scrollback, so that nothing of the previous runs is left
+        Clear::Reset => print!("\x1b[H\x1b[2J\x1b[3J"),
+    }
+
+    println!(
+        "<runar> run {generation} at {}, {reason}, previous exit status {exitstatus}",
+        output::timestamp()
+    );
+}
+
+// Tells that the child is stopped because of a line of its output
+fn print_output_action(action: Action, opts: &Options) {
+    let (flag, consequence) = match action {
+        Action::Restart => ("--restart-on-output", "restarting"),
+        Action::Exit => ("--exit-on-output", "exiting"),
+    };
+
+    output::end_lines();
+    eprintln!(
+        "<runar> {} wrote a line matching {flag}, {consequence}",
+        opts.command[0].to_string_lossy()
+    );
+}
+
+// Runs the build command, the child is only restarted once it succeeds
+fn start_build(
+    build: &OsStr,
+    handler: &mut EventHandler,
+    opts: &Options,
+) -> Result<Option<Pid>, Errno> {
+    let build_pid = match spawn_sh(build, opts) {
+        Ok(pid) => pid,
+        Err(e) => {
+            output::end_lines();
+            eprintln!("<runar> Error: could not run the build: {e}");
+            return Ok(None);
+        }
+    };
+    handler.watch_build(build_pid)?;
 
     if opts.verbose {
-        println!("<runar> child process spawned with pid {child_pid}");
+        output::end_lines();
+        println!("<runar> build process spawned with pid {build_pid}");
+    }
+
+    Ok(Some(build_pid))
+}
+
+// Runs the health check command, the child is healthy if it succeeds
+fn start_probe(
+    health_cmd: &OsStr,
+    handler: &mut EventHandler,
+    opts: &Options,
+) -> Result<Option<Pid>, Errno> {
+    let probe_pid = match spawn_sh(health_cmd, opts) {
+        Ok(pid) => pid,
+        Err(e) => {
+            output::end_lines();
+            eprintln!("<runar> Error: could not run the health check: {e}");
+            return Ok(None);
+        }
+    };
+    handler.watch_probe(probe_pid)?;
+
+    if opts.verbose {
+        output::end_lines();
+        println!("<runar> health check spawned with pid {probe_pid}");
+    }
+
+    Ok(Some(probe_pid))
+}
+
+// Runs cmd with sh in its own process group, so that it can be stopped as a whole
+fn spawn_sh(cmd: &OsStr, opts: &Options) -> io::Result<Pid> {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env(HELPER_VAR, process::id().to_string())
+        .process_group(0);
+    let sigmask = opts.sigmask;
+
+    if let Some(dir) = &opts.chdir {
+        command.current_dir(dir);
+    }
+
+    unsafe {
+        command.pre_exec(move || {
+            sigmask.thread_unblock()?;
+            Ok(())
+        });
+    }
+
+    let pid = command.spawn()?.id() as i32;
+
+    Ok(Pid::from_raw(pid))
+}
+
+// Whether the process was started by a build or health check of ours, it is not part of a run
+// even when it was re-parented to us
+fn is_helper(pid: Pid) -> bool {
+    proc::env_var(pid, HELPER_VAR).is_some_and(|value| value == *process::id().to_string())
+}
+
+// Kills a command started with spawn_sh and everything in its process group
+fn stop_group(pid: Pid) {
+    let pgrp = Pid::from_raw(-pid.as_raw());
+
+    if kill(pgrp, Signal::SIGKILL).is_ok() {
+        while waitpid(pgrp, None).is_ok() {}
+    }
+}
+
+// Reports that the child keeps exiting and that we are giving up on it
+fn print_crash_loop(restarts: &VecDeque<(Instant, u8)>, exitstatus: u8, opts: &Options) {
+    let statuses: Vec<String> = restarts
+        .iter()
+        .map(|(_, status)| status.to_string())
+        .chain([exitstatus.to_string()])
+        .collect();
+
+    output::end_lines();
+    eprintln!(
+        "<runar> {} was restarted {} times within {:?}, giving up",
+        opts.command[0].to_string_lossy(),
+        restarts.len(),
+        opts.within
+    );
+    output::end_lines();
+    eprintln!("<runar> recent exit statuses: {}", statuses.join(", "));
+}
+
+// Reaps all exited children, returns the statuses of the watched ones among them
+fn reap_children(watched: &[Pid], opts: &Options) -> Result<Vec<WaitStatus>, Errno> {
+    let mut statuses = Vec::new();
+
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break, // No more exited children
+            Ok(status) if status.pid().is_some_and(|pid| watched.contains(&pid)) => {
+                statuses.push(status)
+            }
+            Ok(status) => {
+                if opts.verbose {
+                    output::end_lines();
+                    println!("<runar> reaped orphaned process {status:?}");
+                }
+            }
+            Err(e) => return Err(e),
+        }
     }
 
-    Pid::from_raw(child_pid)
+    Ok(statuses)
 }
 
-// Kills all processes in the process group
-fn term_wait_kill(pid: Pid, handler: &mut EventHandler, opts: &Options) {
+// Kills all processes in the process group, and those that left it
+// Processes in the spared process groups, such as a running build, are left alone
+// Returns the status of the child if it was reaped
+fn term_wait_kill(
+    pid: Pid,
+    cgroup: Option<Cgroup>,
+    spare: &[Pid],
+    handler: &mut EventHandler,
+    output: &mut Output,
+    opts: &Options,
+) -> Option<WaitStatus> {
     let pgrp = Pid::from_raw(-pid.as_raw());
+    let mut child_status = None;
+
+    // The processes of the run are recorded before they are signalled, later on only these
+    // are killed. Processes that left the process group can still be found in the cgroup, or
+    // without one by walking the process tree since orphans are re-parented to us
+    let escaped: Vec<Pid> = match cgroup.as_ref().map(Cgroup::pids) {
+        Some(Ok(pids)) => pids,
+        Some(Err(e)) => {
+            output::end_lines();
+            eprintln!("<runar> Could not read cgroup: {e}");
+            Vec::new()
+        }
+        None => proc::descendants(&[Pid::this()])
+            .into_iter()
+            .filter(|&p| !is_helper(p))
+            .collect(),
+    };
+    let escaped: Vec<Pid> = escaped
+        .into_iter()
+        .filter(|&p| unistd::getpgid(Some(p)).is_ok_and(|pg| pg != pid && !spare.contains(&pg)))
+        .collect();
+
+    for &escaped_pid in &escaped {
+        let _ = kill(escaped_pid, Signal::SIGTERM);
+    }
 
     // Send terminate signal to children, giving them time to terminate before we kill everything
     match kill(pgrp, Signal::SIGTERM) {
         Ok(()) => (),
-        Err(Errno::ESRCH) => return, // No processes left in group
+        Err(Errno::ESRCH) if escaped.is_empty() => return None, // No processes left
+        Err(Errno::ESRCH) => (), // Only processes outside of the group are left
         Err(e) => {
+            output::end_lines();
             eprintln!("<runar> Kill got error: {e}");
-            return;
+            return None;
         }
     }
 
-    handler.wait_signals(opts.kill_timer).unwrap();
+    // Every process gets the whole kill timer to exit, each SIGCHLD only tells us to look again
+    let deadline = opts
+        .kill_timer
+        .duration()
+        .map(|timer| Instant::now() + timer);
+    let mut kill_pgrp;
+    loop {
+        // If any process in the process group is still alive, we kill the entire group
+        // This is so that we clean up any orphaned children that are still alive
+        kill_pgrp = false;
+        while let Ok(wait_status) = waitpid(pgrp, Some(WaitPidFlag::WNOHANG)) {
+            if wait_status == WaitStatus::StillAlive {
+                kill_pgrp = true;
+                break;
+            } else if wait_status.pid() == Some(pid) {
+                child_status = Some(wait_status);
+            }
+        }
+
+        let escaped_alive = escaped
+            .iter()
+            .any(|&p| proc::stat(p).is_some_and(|stat| stat.state != 'Z'));
 
-    // If any process in the process group is still alive, we kill the entire group
-    // This is so that we clean up any orphaned children that are still alive
-    let mut kill_pgrp = false;
-    while let Ok(wait_status) = waitpid(pgrp, Some(WaitPidFlag::WNOHANG)) {
-        if wait_status == WaitStatus::StillAlive {
-            kill_pgrp = true;
+        if !kill_pgrp && !escaped_alive {
             break;
         }
+
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => {
+                    PollTimeout::try_from(left).unwrap_or(PollTimeout::MAX)
+                }
+                _ => break,
+            },
+            None => PollTimeout::NONE,
+        };
+
+        // The output is passed on while waiting, a child blocked on a full pipe could not exit
+        if handler.wait_signals(timeout).unwrap() == Event::Output {
+            if let Err(e) = forward_output(handler, output, opts) {
+                output::end_lines();
+                eprintln!("<runar> Could not read output: {e}");
+            }
+        }
     }
 
     if kill_pgrp {
         if opts.verbose {
+            output::end_lines();
             println!("<runar> Some children took too long to exit, will now get SIGKILLed");
         }
         kill(pgrp, Signal::SIGKILL).unwrap();
+
+        // Nothing survives SIGKILL, wait for the group so we know how the child ended
+        while let Ok(wait_status) = waitpid(pgrp, None) {
+            if wait_status.pid() == Some(pid) {
+                child_status = Some(wait_status);
+            }
+        }
+    }
+
+    let stragglers = match cgroup {
+        Some(cgroup) => kill_cgroup(&cgroup).unwrap_or_else(|e| {
+            output::end_lines();
+            eprintln!("<runar> Could not kill cgroup: {e}");
+            0
+        }),
+        None => kill_descendants(&escaped),
+    };
+
+    if stragglers > 0 && opts.verbose {
+        let processes = if stragglers == 1 {
+            "process"
+        } else {
+            "processes"
+        };
+        output::end_lines();
+        println!("<runar> Killed {stragglers} straggling {processes} outside of the process group");
     }
+
+    child_status
+}
+
+// Kills the given processes that are still alive, along with anything they started since
+// Returns how many there were
+fn kill_descendants(escaped: &[Pid]) -> usize {
+    let alive: Vec<Pid> = escaped
+        .iter()
+        .copied()
+        .filter(|&p| proc::stat(p).is_some_and(|stat| stat.state != 'Z'))
+        .collect();
+
+    alive
+        .iter()
+        .copied()
+        .chain(proc::descendants(&alive))
+        .filter(|&p| kill(p, Signal::SIGKILL).is_ok())
+        .count()
+}
+
+// Kills everything left in the cgroup and makes sure that it is empty
+// Returns how many processes were killed
+fn kill_cgroup(cgroup: &Cgroup) -> Result<usize, Errno> {
+    if !cgroup.is_populated()? {
+        return Ok(0);
+    }
+
+    let stragglers = cgroup.pids()?.len();
+    cgroup.kill()?;
+
+    // SIGKILL can not be ignored, the wait does not depend on --kill-timer
+    if !cgroup.wait_empty(CGROUP_KILL_TIMEOUT)? {
+        output::end_lines();
+        eprintln!("<runar> Processes are still left in the cgroup after SIGKILL");
+    }
+
+    Ok(stragglers)
 }
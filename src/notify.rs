@@ -0,0 +1,129 @@
+use std::fs::Permissions;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, io, process};
+
+use nix::errno::Errno;
+use nix::unistd::{self, Gid, Uid};
+
+// The messages of the sd_notify protocol that runar acts on
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    Ready,
+    Reloading,
+    Stopping,
+    Watchdog,
+    Status(String),
+    // Sent by a runar to the runar of a Procfile that runs it, each time it restarts its command
+    Restarted,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::Ready => write!(f, "READY=1"),
+            Message::Reloading => write!(f, "RELOADING=1"),
+            Message::Stopping => write!(f, "STOPPING=1"),
+            Message::Watchdog => write!(f, "WATCHDOG=1"),
+            Message::Status(status) => write!(f, "STATUS={status}"),
+            Message::Restarted => write!(f, "X_RUNAR_RESTARTED=1"),
+        }
+    }
+}
+
+// A socket that services send their sd_notify messages to, they find it through NOTIFY_SOCKET
+pub struct NotifySocket {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl NotifySocket {
+    // Only the child, which runs as the given user and group, may send to the socket
+    pub fn bind(name: &str, uid: Option<Uid>, gid: Option<Gid>) -> Result<NotifySocket, Errno> {
+        let path = env::temp_dir().join(format!("runar-{}-{name}.sock", process::id()));
+
+        // A socket left behind by an earlier runar with the same pid would make bind fail
+        let _ = fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path).map_err(errno)?;
+        let notify = NotifySocket { socket, path };
+
+        fs::set_permissions(&notify.path, Permissions::from_mode(0o600)).map_err(errno)?;
+        unistd::chown(&notify.path, uid, gid)?;
+        notify.socket.set_nonblocking(true).map_err(errno)?;
+
+        Ok(notify)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Receives all pending messages, those that runar does not act on are skipped
+    pub fn recv(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut buf = [0; 4096];
+
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            messages.extend(parse(&buf[..len]));
+        }
+
+        messages
+    }
+}
+
+impl AsFd for NotifySocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Sends a message to the service manager that runar itself runs under, if there is one
+pub fn send(message: &Message) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    let message = message.to_string();
+
+    // Paths starting with @ are in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+
+    socket.send_to_addr(message.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+// A datagram holds one KEY=VALUE assignment per line
+fn parse(datagram: &[u8]) -> Vec<Message> {
+    String::from_utf8_lossy(datagram)
+        .lines()
+        .filter_map(|line| match line.split_once('=')? {
+            ("READY", "1") => Some(Message::Ready),
+            ("RELOADING", "1") => Some(Message::Reloading),
+            ("STOPPING", "1") => Some(Message::Stopping),
+            ("WATCHDOG", "1") => Some(Message::Watchdog),
+            ("STATUS", status) => Some(Message::Status(status.to_string())),
+            ("X_RUNAR_RESTARTED", "1") => Some(Message::Restarted),
+            _ => None,
+        })
+        .collect()
+}
+
+fn errno(e: io::Error) -> Errno {
+    Errno::from_raw(e.raw_os_error().unwrap_or(0))
+}
@@ -0,0 +1,307 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::libc;
+use regex_lite::Regex;
+
+use crate::event_handler::Stream;
+use crate::parse_args::Options;
+
+// Only this much of a line is matched against the patterns
+const MAX_LINE: usize = 64 * 1024;
+
+// Whether our stdout and stderr end in a line of the child that was written without its newline
+static STDOUT_OPEN: AtomicBool = AtomicBool::new(false);
+static STDERR_OPEN: AtomicBool = AtomicBool::new(false);
+
+// What runar does when a line of output matches a pattern
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Restart,
+    Exit,
+}
+
+// Passes the output of the child on as it comes, decorating the lines as asked and matching
+// them against the --restart-on-output and --exit-on-output patterns
+// A line is only ended by us when a decoration or a message of runar would end up within it
+pub struct Output {
+    generation: u32,
+    stdout: Line,
+    stderr: Line,
+    log: Option<Log>,
+}
+
+// The line of a stream that the child is writing
+#[derive(Default)]
+struct Line {
+    // What it wrote of the line so far, to match against the patterns once it ends
+    text: Vec<u8>,
+    // Whether it wrote part of the line
+    open: bool,
+}
+
+impl Output {
+    pub fn new(log: Option<Log>) -> Output {
+        Output {
+            generation: 0,
+            stdout: Line::default(),
+            stderr: Line::default(),
+            log,
+        }
+    }
+
+    // The lines that are forwarded from now on belong to the given run
+    pub fn start_run(&mut self, generation: u32) {
+        self.generation = generation;
+    }
+
+    // The run will not write anything more, its unterminated last lines are not matched
+    pub fn finish_run(&mut self) {
+        self.stdout = Line::default();
+        self.stderr = Line::default();
+    }
+
+    // Returns the action of the first complete line that matches a pattern, if any
+    pub fn forward(&mut self, stream: Stream, data: &[u8], opts: &Options) -> Option<Action> {
+        let Output {
+            generation,
+            stdout,
+            stderr,
+            log,
+        } = self;
+
+        let line = match stream {
+            Stream::Stdout => stdout,
+            Stream::Stderr => stderr,
+        };
+
+        let mut action = None;
+
+        for part in data.split_inclusive(|&b| b == b'\n') {
+            // A message of runar in between ended the line, the rest is decorated anew
+            let starts = !line.open || !is_open(stream).load(Ordering::Relaxed);
+            write(stream, *generation, part, starts, log, opts);
+
+            let text = part.strip_suffix(b"\n").unwrap_or(part);
+            let room = MAX_LINE.saturating_sub(line.text.len());
+            line.text.extend(text.iter().take(room));
+            line.open = text.len() == part.len();
+
+            if !line.open {
+                let text = mem::take(&mut line.text);
+                if action.is_none() {
+                    action = matching_action(&String::from_utf8_lossy(&text), opts);
+                }
+            }
+        }
+
+        action
+    }
+}
+
+// Ends the lines that the child left open on our stdout and stderr, for a message of runar
+pub fn end_lines() {
+    end_line(Stream::Stdout);
+    end_line(Stream::Stderr);
+}
+
+fn end_line(stream: Stream) {
+    if is_open(stream).swap(false, Ordering::Relaxed) {
+        let _ = match stream {
+            Stream::Stdout => io::stdout().write_all(b"\n"),
+            Stream::Stderr => io::stderr().write_all(b"\n"),
+        };
+    }
+}
+
+fn is_open(stream: Stream) -> &'static AtomicBool {
+    match stream {
+        Stream::Stdout => &STDOUT_OPEN,
+        Stream::Stderr => &STDERR_OPEN,
+    }
+}
+
+// The file that the output is copied to, rotated once it grows past --log-max-size
+pub struct Log {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // Whether the file ends in a line that was written without its newline
+    open: bool,
+}
+
+impl Log {
+    pub fn open(path: &Path) -> io::Result<Log> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Log {
+            path: path.to_path_buf(),
+            file,
+            size,
+            open: false,
+        })
+    }
+
+    fn write(&mut self, line: &[u8], opts: &Options) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > opts.log_max_size {
+            self.rotate(opts.log_keep)?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        self.open = !line.ends_with(b"\n");
+
+        Ok(())
+    }
+
+    // Moves out.log to out.log.1, out.log.1 to out.log.2 and so on, the oldest one is dropped
+    fn rotate(&mut self, keep: u32) -> io::Result<()> {
+        for n in (1..keep).rev() {
+            match fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+
+        match keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated(&self.path, 1))?,
+        }
+
+        *self = Log::open(&self.path)?;
+
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
+
+// Whether path is the log file or one of its rotations, which should not count as changes
+pub fn is_log_file(path: &Path, log_file: &Path) -> bool {
+    let Some(suffix) = path
+        .as_os_str()
+        .as_bytes()
+        .strip_prefix(log_file.as_os_str().as_bytes())
+    else {
+        return false;
+    };
+
+    match suffix.strip_prefix(b".") {
+        Some(n) => !n.is_empty() && n.iter().all(u8::is_ascii_digit),
+        None => suffix.is_empty(),
+    }
+}
+
+// Writes part of a line, with its decoration when it starts the line, in a single write and
+// copies it to the log file
+fn write(
+    stream: Stream,
+    generation: u32,
+    part: &[u8],
+    starts: bool,
+    log: &mut Option<Log>,
+    opts: &Options,
+) {
+    let mut tags = Vec::new();
+
+    if opts.timestamps {
+        tags.push(timestamp());
+    }
+
+    if opts.run_numbers {
+        tags.push(format!("#{generation}"));
+    }
+
+    if opts.mark_stderr && stream == Stream::Stderr {
+        tags.push("err".to_string());
+    }
+
+    let decorates = starts && !tags.is_empty();
+
+    // Both streams may go to the same terminal, a decoration does not land within a line of the
+    // other one either
+    if decorates {
+        end_line(match stream {
+            Stream::Stdout => Stream::Stderr,
+            Stream::Stderr => Stream::Stdout,
+        });
+    }
+
+    // The leading newline ends a line left open by an earlier run, before a decoration
+    let mut decorated = Vec::with_capacity(part.len() + 32);
+    decorated.push(b'\n');
+    if decorates {
+        decorated.extend_from_slice(tags.join(" ").as_bytes());
+        decorated.extend_from_slice(b" | ");
+    }
+    decorated.extend_from_slice(part);
+
+    let from = |open: bool| usize::from(!(decorates && open));
+
+    // There is nobody to tell if our own output is gone
+    let open = is_open(stream);
+    let _ = match stream {
+        Stream::Stdout => {
+            let mut stdout = io::stdout().lock();
+            stdout
+                .write_all(&decorated[from(open.load(Ordering::Relaxed))..])
+                .and_then(|()| stdout.flush())
+        }
+        Stream::Stderr => io::stderr().write_all(&decorated[from(open.load(Ordering::Relaxed))..]),
+    };
+    open.store(!part.ends_with(b"\n"), Ordering::Relaxed);
+
+    if let Some(file) = log {
+        if let Err(e) = file.write(&decorated[from(file.open)..], opts) {
+            // The output still reaches our own stdout and stderr
+            end_lines();
+            eprintln!(
+                "<runar> Error: {}: {e}, no longer logging",
+                file.path.display()
+            );
+            *log = None;
+        }
+    }
+}
+
+// The local time of day, such as 13:37:00.123
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() as libc::time_t;
+
+    // localtime_r only writes to tm, which is ours alone
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        now.subsec_millis()
+    )
+}
+
+fn matching_action(line: &str, opts: &Options) -> Option<Action> {
+    let matches = |pattern: &Option<Regex>| pattern.as_ref().is_some_and(|re| re.is_match(line));
+
+    if matches(&opts.exit_on_output) {
+        Some(Action::Exit)
+    } else if matches(&opts.restart_on_output) {
+        Some(Action::Restart)
+    } else {
+        None
+    }
+}
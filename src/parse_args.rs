@@ -1,10 +1,21 @@
-use std::ffi::OsString;
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::time::Duration;
 
+use nix::libc;
 use nix::poll::PollTimeout;
+use nix::sched::CpuSet;
+use nix::sys::resource::{self, rlim_t, Resource, RLIM_INFINITY};
 use nix::sys::signal::{self, SigSet};
+use nix::unistd::{Gid, Group, Uid, User};
 
 use pico_args::Arguments;
+use regex_lite::Regex;
+
+use crate::dotenv;
 
 const HELP: &str = concat!(
     env!("CARGO_PKG_NAME"),
@@ -16,24 +27,116 @@ const HELP: &str = concat!(
     "\
 USAGE:
     runar [FLAGS] -- <COMMAND> [ARGS...]
+    runar [-v] -P <procfile>
 
 FLAGS:
-    -f, --file <filename>           path to file or directory to watch, multiple flags allowed
-    -r, --recursive                 recursively watch directories
-    -e, --exit                      exit runar if COMMAND returns status code 0
-    -E, --exit-on-error             exit runar if COMMAND returns statuse code >0
-    -s, --restart                   restart COMMAND if it returns status code 0
-    -S, --restart-on-error          restart COMMAND if it returns status code >0
-    -k, --kill-timer <kill-timer>   time in milliseconds until kill signal is sent (default: 5000)
-    -v, --verbose                   increases the level of verbosity
-    -h, --help                      Prints help information
+    -f, --file <filename>                 path to file or directory to watch, multiple flags allowed
+    -r, --recursive                       recursively watch directories
+    -e, --exit                            exit runar if COMMAND returns status code 0
+    -E, --exit-on-error                   exit runar if COMMAND returns statuse code >0
+    -s, --restart                         restart COMMAND if it returns status code 0
+    -S, --restart-on-error                restart COMMAND if it returns status code >0
+    -k, --kill-timer <kill-timer>         time in milliseconds until kill signal is sent (default: 5000)
+        --postpone                        do not run COMMAND until files change
+        --no-interrupt                    let COMMAND finish when files change, and run it again after
+        --timeout <duration>              stop COMMAND when a run takes longer, the run exits with 124
+        --every <duration>                run COMMAND again on an interval
+        --overlap <skip|restart>          when COMMAND is still running on the interval, skip the run
+                                          or restart COMMAND (default: skip)
+        --build <cmd>                     run cmd with sh before COMMAND is started and on file changes,
+                                          COMMAND is only restarted if the build succeeds
+        --notify                          give COMMAND a NOTIFY_SOCKET for sd_notify messages, a run
+                                          only counts as healthy for --backoff once it sent READY=1
+        --watchdog <duration>             with --notify, stop COMMAND when it does not send WATCHDOG=1
+                                          within duration
+        --liveness <duration>             restart COMMAND when it writes no output and sends no
+                                          WATCHDOG=1 for duration
+        --health-cmd <cmd>                run cmd with sh on an interval while COMMAND runs, COMMAND is
+                                          restarted when cmd fails --health-retries times in a row
+        --health-interval <duration>      time between health checks, a check that takes longer fails
+                                          (default: 30s)
+        --health-retries <n>              failed health checks in a row before a restart (default: 3)
+        --restart-on-output <regex>       restart COMMAND when a line of its output matches regex
+        --exit-on-output <regex>          stop COMMAND and exit when a line of its output matches regex
+        --clear[=reset]                   clear the terminal before COMMAND is run again and print why,
+                                          reset also clears the scrollback
+        --timestamps                      prefix the lines of COMMAND's output with the time of day
+        --run-numbers                     prefix the lines of COMMAND's output with the number of the
+                                          run that wrote them, such as #3
+        --mark-stderr                     prefix the lines COMMAND writes to stderr with err
+        --log-file <file>                 also write the output of COMMAND to file, which is not watched
+        --log-max-size <size>             rotate the log file once it grows past size (default: 10M)
+        --log-keep <n>                    number of rotated log files to keep, such as file.1 (default: 5)
+        --max-rss <size>                  restart COMMAND when the resident memory of its processes
+                                          goes over size, checked every second
+        --backoff <initial,max,factor>    delay restarts after COMMAND exits or fails a check, growing
+                                          by factor up to max
+        --healthy-uptime <duration>       uptime after which the backoff is reset (default: 10s)
+        --max-restarts <n>                give up after COMMAND exits or fails a check and is restarted
+                                          n times
+        --within <duration>               time window counted by --max-restarts (default: 60s)
+        --reraise                         when terminated by a signal, exit by re-raising it
+        --cgroup <dir>                    run COMMAND in a new cgroup below the delegated cgroup v2 dir
+        --chdir <dir>                     run COMMAND in dir
+        --env <key=value>                 set an environment variable for COMMAND, multiple flags allowed
+        --env-clear                       do not pass the environment of runar on to COMMAND
+        --env-file <file>                 read environment variables for COMMAND from a dotenv file,
+                                          the file is watched and COMMAND restarted when it changes
+        --user <user>                     run COMMAND as user, requires root
+        --group <group>                   run COMMAND as group, requires root (default: group of user)
+        --rlimit <resource=limit,...>     set resource limits for COMMAND, such as nofile=1024,as=2G,
+                                          limits may be unlimited or given as soft:hard
+        --nice <n>                        run COMMAND with niceness n
+        --cpu-affinity <cpus>             run COMMAND on the given cpus only, such as 0,2-3
+    -P, --procfile <file>                 run the processes of a Procfile instead of COMMAND,
+                                          each line is name: [FLAGS --] COMMAND, where FLAGS may
+                                          also hold --after <name> to start after another process
+                                          is ready, and --ready <exit|port:N|file:PATH|notify> for
+                                          when this process is ready (default: once started),
+                                          processes after a restarted one are restarted after it
+    -v, --verbose                         increases the level of verbosity
+    -h, --help                            Prints help information
 
 ARGS:
     <COMMAND>    the COMMAND to execute
     [ARGS...]    the arguments to COMMAND
+
+DURATIONS:
+    <duration>    a number with an optional unit of ms, s, m or h, plain numbers are milliseconds
+
+SIZES:
+    <size>        a number with an optional unit of K, M, G or T, plain numbers are bytes
 "
 );
 
+// Restart delay growing from initial to max by factor for each fast failure
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub factor: f64,
+}
+
+// How the terminal is cleared before a new run of the child
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Clear {
+    Screen,
+    Reset,
+}
+
+// What to do when the --every interval passes while the child is still running
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overlap {
+    Skip,
+    Restart,
+}
+
+#[derive(Clone, Copy)]
+pub struct Rlimit {
+    pub resource: Resource,
+    pub soft: rlim_t,
+    pub hard: rlim_t,
+}
+
 pub struct Options {
     pub exit_on_zero: bool,
     pub exit_on_error: bool,
@@ -42,11 +145,74 @@ pub struct Options {
     pub recursive: bool,
     pub verbose: bool,
     pub kill_timer: PollTimeout,
+    pub postpone: bool,
+    pub no_interrupt: bool,
+    pub timeout: Option<Duration>,
+    pub every: Option<Duration>,
+    pub overlap: Overlap,
+    pub build: Option<OsString>,
+    pub notify: bool,
+    pub watchdog: Option<Duration>,
+    pub liveness: Option<Duration>,
+    pub health_cmd: Option<OsString>,
+    pub health_interval: Duration,
+    pub health_retries: u32,
+    pub max_rss: Option<u64>,
+    pub restart_on_output: Option<Regex>,
+    pub exit_on_output: Option<Regex>,
+    pub clear: Option<Clear>,
+    pub timestamps: bool,
+    pub run_numbers: bool,
+    pub mark_stderr: bool,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: u64,
+    pub log_keep: u32,
+    pub backoff: Option<Backoff>,
+    pub healthy_uptime: Duration,
+    pub max_restarts: Option<usize>,
+    pub within: Duration,
+    pub reraise: bool,
+    pub cgroup: Option<PathBuf>,
+    pub chdir: Option<OsString>,
+    pub env: Vec<(OsString, OsString)>,
+    pub env_clear: bool,
+    pub env_file: Option<PathBuf>,
+    pub user: Option<User>,
+    pub group: Option<Group>,
+    pub rlimits: Vec<Rlimit>,
+    pub nice: Option<i32>,
+    pub cpu_affinity: Option<CpuSet>,
+    pub procfile: Option<PathBuf>,
     pub command: Vec<OsString>,
     pub files: Vec<OsString>,
     pub sigmask: SigSet,
 }
 
+impl Options {
+    // Whether runar reads the output of the child instead of letting it write to ours directly
+    pub fn capture_output(&self) -> bool {
+        self.liveness.is_some()
+            || self.restart_on_output.is_some()
+            || self.exit_on_output.is_some()
+            || self.timestamps
+            || self.run_numbers
+            || self.mark_stderr
+            || self.log_file.is_some()
+    }
+
+    // The user and group the child runs as, the group of the user unless --group is given
+    pub fn owner(&self) -> (Option<Uid>, Option<Gid>) {
+        let uid = self.user.as_ref().map(|user| user.uid);
+        let gid = match (&self.group, &self.user) {
+            (Some(group), _) => Some(group.gid),
+            (None, Some(user)) => Some(user.gid),
+            (None, None) => None,
+        };
+
+        (uid, gid)
+    }
+}
+
 pub fn parse_args() -> Result<Options, ExitCode> {
     let args: Vec<OsString> = std::env::args_os().collect();
 
@@ -60,6 +226,7 @@ pub fn parse_args() -> Result<Options, ExitCode> {
 
     args.remove(0); // Remove program name
 
+    let flags = args.clone();
     let mut args = Arguments::from_vec(args);
 
     if args.contains(["-h", "--help"]) {
@@ -67,26 +234,57 @@ pub fn parse_args() -> Result<Options, ExitCode> {
         return Err(ExitCode::SUCCESS);
     }
 
-    let Some(mut command) = command else {
-        eprintln!("<runar> Error: Expected command after -- argument");
-        println!("{HELP}");
-        return Err(ExitCode::FAILURE);
+    let procfile = match args.opt_value_from_os_str(["-P", "--procfile"], parse_os_string) {
+        Ok(procfile) => procfile.map(PathBuf::from),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
     };
 
-    command.remove(0); // Remove --
-
-    if command.is_empty() {
-        eprintln!("<runar> Error: Expected command after -- argument");
-        println!("{HELP}");
-        return Err(ExitCode::FAILURE);
+    // The flags for the processes go on their lines in the Procfile, only -v is for us
+    if procfile.is_some() {
+        if let Some(flag) = flags.iter().find(|flag| !is_procfile_flag(flag)) {
+            eprintln!(
+                "<runar> Error: {} cannot be used with -P, give it to the processes in the Procfile",
+                flag.to_string_lossy()
+            );
+            return Err(ExitCode::FAILURE);
+        }
     }
 
+    // The processes of a Procfile are run instead of a command
+    let command = match (command, &procfile) {
+        (None, Some(_)) => Vec::new(),
+        (Some(_), Some(_)) => {
+            eprintln!("<runar> Error: Expected either a command or a Procfile");
+            return Err(ExitCode::FAILURE);
+        }
+        (Some(mut command), None) if command.len() > 1 => {
+            command.remove(0); // Remove --
+            command
+        }
+        (_, None) => {
+            eprintln!("<runar> Error: Expected command after -- argument");
+            println!("{HELP}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
     let exit_on_zero = args.contains(["-e", "--exit"]);
     let exit_on_error = args.contains(["-E", "--exit-on-error"]);
     let restart_on_zero = args.contains(["-s", "--restart"]);
     let restart_on_error = args.contains(["-S", "--restart-on-error"]);
     let recursive = args.contains(["-r", "--recursive"]);
     let verbose = args.contains(["-v", "--verbose"]);
+    let reraise = args.contains("--reraise");
+    let env_clear = args.contains("--env-clear");
+    let postpone = args.contains("--postpone");
+    let no_interrupt = args.contains("--no-interrupt");
+    let notify = args.contains("--notify");
+    let timestamps = args.contains("--timestamps");
+    let run_numbers = args.contains("--run-numbers");
+    let mark_stderr = args.contains("--mark-stderr");
 
     let kill_timer = match args.opt_value_from_str::<_, i32>(["-k", "--kill-timer"]) {
         Ok(None) => PollTimeout::from(5000_u16),
@@ -97,6 +295,273 @@ pub fn parse_args() -> Result<Options, ExitCode> {
         }
     };
 
+    let backoff = match args.opt_value_from_fn("--backoff", parse_backoff) {
+        Ok(backoff) => backoff,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let timeout = match args.opt_value_from_fn("--timeout", parse_duration) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let every = match args.opt_value_from_fn("--every", parse_interval) {
+        Ok(every) => every,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let overlap = match args.opt_value_from_fn("--overlap", parse_overlap) {
+        Ok(overlap) => overlap.unwrap_or(Overlap::Skip),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let build = match args.opt_value_from_os_str("--build", parse_os_string) {
+        Ok(build) => build,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let watchdog = match args.opt_value_from_fn("--watchdog", parse_interval) {
+        Ok(watchdog) => watchdog,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    if watchdog.is_some() && !notify {
+        eprintln!("<runar> Error: --watchdog requires --notify");
+        return Err(ExitCode::FAILURE);
+    }
+
+    let liveness = match args.opt_value_from_fn("--liveness", parse_interval) {
+        Ok(liveness) => liveness,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let health_cmd = match args.opt_value_from_os_str("--health-cmd", parse_os_string) {
+        Ok(health_cmd) => health_cmd,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let health_interval = match args.opt_value_from_fn("--health-interval", parse_interval) {
+        Ok(health_interval) => health_interval.unwrap_or(Duration::from_secs(30)),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let health_retries = match args.opt_value_from_fn("--health-retries", parse_retries) {
+        Ok(health_retries) => health_retries.unwrap_or(3),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let restart_on_output = match args.opt_value_from_fn("--restart-on-output", parse_regex) {
+        Ok(restart_on_output) => restart_on_output,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let exit_on_output = match args.opt_value_from_fn("--exit-on-output", parse_regex) {
+        Ok(exit_on_output) => exit_on_output,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    // --clear=reset is matched as a whole, it is the only form of --clear with a value
+    let clear = if args.contains("--clear=reset") {
+        Some(Clear::Reset)
+    } else if args.contains("--clear") {
+        Some(Clear::Screen)
+    } else {
+        None
+    };
+
+    let log_file = match args.opt_value_from_os_str("--log-file", parse_log_file) {
+        Ok(log_file) => log_file,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let log_max_size = match args.opt_value_from_fn("--log-max-size", parse_size) {
+        Ok(log_max_size) => log_max_size.unwrap_or(10 << 20),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let log_keep = match args.opt_value_from_str("--log-keep") {
+        Ok(log_keep) => log_keep.unwrap_or(5),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let max_rss = match args.opt_value_from_fn("--max-rss", parse_size) {
+        Ok(max_rss) => max_rss,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let healthy_uptime = match args.opt_value_from_fn("--healthy-uptime", parse_duration) {
+        Ok(healthy_uptime) => healthy_uptime.unwrap_or(Duration::from_secs(10)),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let max_restarts = match args.opt_value_from_str("--max-restarts") {
+        Ok(max_restarts) => max_restarts,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let within = match args.opt_value_from_fn("--within", parse_duration) {
+        Ok(within) => within.unwrap_or(Duration::from_secs(60)),
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let cgroup = match args.opt_value_from_os_str("--cgroup", parse_cgroup) {
+        Ok(cgroup) => cgroup,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let chdir = match args.opt_value_from_os_str("--chdir", parse_os_string) {
+        Ok(chdir) => chdir,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    // The child could not be started in a directory that is not there
+    if let Some(Err(e)) = chdir.as_deref().map(check_dir) {
+        eprintln!("<runar> Error: --chdir {e}");
+        return Err(ExitCode::FAILURE);
+    }
+
+    let user = match args.opt_value_from_fn("--user", parse_user) {
+        Ok(user) => user,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let group = match args.opt_value_from_fn("--group", parse_group) {
+        Ok(group) => group,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    if (user.is_some() || group.is_some()) && !Uid::effective().is_root() {
+        eprintln!("<runar> Error: --user and --group require runar to run as root");
+        return Err(ExitCode::FAILURE);
+    }
+
+    let nice = match args.opt_value_from_str("--nice") {
+        Ok(nice) => nice,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    if let Some(nice) = nice.filter(|&nice| nice < lowest_nice()) {
+        eprintln!(
+            "<runar> Error: --nice {nice} requires root, the lowest niceness allowed is {}",
+            lowest_nice()
+        );
+        return Err(ExitCode::FAILURE);
+    }
+
+    let cpu_affinity = match args.opt_value_from_fn("--cpu-affinity", parse_cpu_set) {
+        Ok(cpu_affinity) => cpu_affinity,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
+    let mut rlimits = Vec::new();
+
+    loop {
+        match args.opt_value_from_fn("--rlimit", parse_rlimits) {
+            Ok(None) => break,
+            Ok(Some(limits)) => rlimits.extend(limits),
+            Err(e) => {
+                eprintln!("<runar> Error: {e}");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+    }
+
+    let mut env = Vec::new();
+
+    loop {
+        match args.opt_value_from_os_str("--env", parse_env) {
+            Ok(None) => break,
+            Ok(Some(var)) => env.push(var),
+            Err(e) => {
+                eprintln!("<runar> Error: {e}");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+    }
+
+    let env_file = match args.opt_value_from_os_str("--env-file", parse_env_file) {
+        Ok(env_file) => env_file,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+
     let mut files = Vec::new();
 
     loop {
@@ -110,6 +575,11 @@ pub fn parse_args() -> Result<Options, ExitCode> {
         };
     }
 
+    // Changes to the env file should restart the command with the new values
+    if let Some(env_file) = &env_file {
+        files.push(env_file.clone().into_os_string());
+    }
+
     let remaining = args.finish();
 
     if !remaining.is_empty() {
@@ -132,8 +602,331 @@ pub fn parse_args() -> Result<Options, ExitCode> {
         recursive,
         verbose,
         kill_timer,
+        postpone,
+        no_interrupt,
+        timeout,
+        every,
+        overlap,
+        build,
+        notify,
+        watchdog,
+        liveness,
+        health_cmd,
+        health_interval,
+        health_retries,
+        max_rss,
+        restart_on_output,
+        exit_on_output,
+        timestamps,
+        run_numbers,
+        mark_stderr,
+        clear,
+        log_file,
+        log_max_size,
+        log_keep,
+        backoff,
+        healthy_uptime,
+        max_restarts,
+        within,
+        reraise,
+        cgroup,
+        chdir,
+        env,
+        env_clear,
+        env_file,
+        user,
+        group,
+        rlimits,
+        nice,
+        cpu_affinity,
+        procfile,
         command,
         files,
         sigmask,
     })
 }
+
+// Parses durations such as 500ms, 30s, 5m or 1h, plain numbers are milliseconds
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (value, unit) = arg.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration '{arg}'"))?;
+
+    let seconds = match unit {
+        "" | "ms" => return Ok(Duration::from_millis(value)),
+        "s" => Some(value),
+        "m" => value.checked_mul(60),
+        "h" => value.checked_mul(60 * 60),
+        _ => return Err(format!("invalid duration unit '{unit}'")),
+    };
+
+    seconds
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{arg}' is too large"))
+}
+
+// Parses sizes such as 512, 64K, 10M or 2G, with binary units
+fn parse_size(arg: &str) -> Result<u64, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (value, unit) = arg.split_at(split);
+
+    let value: u64 = value.parse().map_err(|_| format!("invalid size '{arg}'"))?;
+
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        "T" | "t" => 1 << 40,
+        _ => return Err(format!("invalid size unit '{unit}'")),
+    };
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{arg}' is too large"))
+}
+
+fn parse_interval(arg: &str) -> Result<Duration, String> {
+    match parse_duration(arg)? {
+        Duration::ZERO => Err("interval must be greater than 0".to_string()),
+        interval => Ok(interval),
+    }
+}
+
+fn parse_retries(arg: &str) -> Result<u32, String> {
+    match arg.parse() {
+        Ok(0) => Err("retries must be at least 1".to_string()),
+        Ok(retries) => Ok(retries),
+        Err(_) => Err(format!("invalid number '{arg}'")),
+    }
+}
+
+fn parse_regex(arg: &str) -> Result<Regex, String> {
+    Regex::new(arg).map_err(|e| format!("invalid regex '{arg}': {e}"))
+}
+
+fn parse_overlap(arg: &str) -> Result<Overlap, String> {
+    match arg {
+        "skip" => Ok(Overlap::Skip),
+        "restart" => Ok(Overlap::Restart),
+        _ => Err(format!("expected skip or restart, got '{arg}'")),
+    }
+}
+
+fn parse_backoff(arg: &str) -> Result<Backoff, String> {
+    let [initial, max, factor] = arg.split(',').collect::<Vec<_>>()[..] else {
+        return Err("expected <initial,max,factor>".to_string());
+    };
+
+    let initial = parse_duration(initial)?;
+    let max = parse_duration(max)?;
+    let factor: f64 = factor
+        .parse()
+        .map_err(|_| format!("invalid factor '{factor}'"))?;
+
+    if !factor.is_finite() || factor < 1.0 {
+        return Err("factor must be a number of at least 1".to_string());
+    }
+
+    if initial > max {
+        return Err("initial delay must not be greater than max".to_string());
+    }
+
+    Ok(Backoff {
+        initial,
+        max,
+        factor,
+    })
+}
+
+fn check_dir(dir: &OsStr) -> Result<(), String> {
+    let dir = Path::new(dir);
+
+    match dir.metadata() {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(format!("{} is not a directory", dir.display())),
+        Err(e) => Err(format!("{}: {e}", dir.display())),
+    }
+}
+
+fn parse_cgroup(arg: &OsStr) -> Result<PathBuf, String> {
+    let dir = PathBuf::from(arg);
+
+    if !dir.join("cgroup.procs").is_file() {
+        return Err(format!("{} is not a cgroup v2 directory", dir.display()));
+    }
+
+    Ok(dir)
+}
+
+fn parse_os_string(arg: &OsStr) -> Result<OsString, String> {
+    Ok(arg.to_os_string())
+}
+
+fn parse_env(arg: &OsStr) -> Result<(OsString, OsString), String> {
+    let bytes = arg.as_bytes();
+
+    match bytes.iter().position(|&b| b == b'=') {
+        Some(split) if split > 0 => Ok((
+            OsStr::from_bytes(&bytes[..split]).to_os_string(),
+            OsStr::from_bytes(&bytes[split + 1..]).to_os_string(),
+        )),
+        _ => Err(format!(
+            "expected KEY=VALUE, got '{}'",
+            arg.to_string_lossy()
+        )),
+    }
+}
+
+// Whether a flag is one of -P and -v, or the path that goes with -P
+fn is_procfile_flag(flag: &OsStr) -> bool {
+    let flag = flag.to_string_lossy();
+
+    match (flag.strip_prefix("--"), flag.strip_prefix('-')) {
+        (Some(long), _) => long == "procfile" || long == "verbose",
+        (None, Some(short)) => short.chars().all(|c| c == 'P' || c == 'v'),
+        (None, None) => true,
+    }
+}
+
+// The directory of the log file is made absolute, so that it can be told apart from watched files
+fn parse_log_file(arg: &OsStr) -> Result<PathBuf, String> {
+    let path = PathBuf::from(arg);
+
+    let Some(name) = path.file_name() else {
+        return Err(format!("invalid log file '{}'", path.display()));
+    };
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| format!("{}: {e}", dir.display()))?;
+
+    Ok(dir.join(name))
+}
+
+fn parse_env_file(arg: &OsStr) -> Result<PathBuf, String> {
+    let path = PathBuf::from(arg);
+
+    dotenv::read(&path)?;
+
+    Ok(path)
+}
+
+// Users may be given by name or uid
+fn parse_user(arg: &str) -> Result<User, String> {
+    let user = match arg.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(arg),
+    };
+
+    match user {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(format!("no such user '{arg}'")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Groups may be given by name or gid
+fn parse_group(arg: &str) -> Result<Group, String> {
+    let group = match arg.parse() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(arg),
+    };
+
+    match group {
+        Ok(Some(group)) => Ok(group),
+        Ok(None) => Err(format!("no such group '{arg}'")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parses comma separated limits such as nofile=1024,core=unlimited,as=1G:2G
+fn parse_rlimits(arg: &str) -> Result<Vec<Rlimit>, String> {
+    let mut rlimits = Vec::new();
+
+    for limit in arg.split(',') {
+        let Some((name, value)) = limit.split_once('=') else {
+            return Err(format!("expected resource=limit, got '{limit}'"));
+        };
+
+        let resource = match name {
+            "as" => Resource::RLIMIT_AS,
+            "core" => Resource::RLIMIT_CORE,
+            "cpu" => Resource::RLIMIT_CPU,
+            "data" => Resource::RLIMIT_DATA,
+            "fsize" => Resource::RLIMIT_FSIZE,
+            "locks" => Resource::RLIMIT_LOCKS,
+            "memlock" => Resource::RLIMIT_MEMLOCK,
+            "msgqueue" => Resource::RLIMIT_MSGQUEUE,
+            "nice" => Resource::RLIMIT_NICE,
+            "nofile" => Resource::RLIMIT_NOFILE,
+            "nproc" => Resource::RLIMIT_NPROC,
+            "rss" => Resource::RLIMIT_RSS,
+            "rtprio" => Resource::RLIMIT_RTPRIO,
+            "rttime" => Resource::RLIMIT_RTTIME,
+            "sigpending" => Resource::RLIMIT_SIGPENDING,
+            "stack" => Resource::RLIMIT_STACK,
+            _ => return Err(format!("unknown resource '{name}'")),
+        };
+
+        let (soft, hard) = value.split_once(':').unwrap_or((value, value));
+
+        rlimits.push(Rlimit {
+            resource,
+            soft: parse_rlim(soft)?,
+            hard: parse_rlim(hard)?,
+        });
+    }
+
+    Ok(rlimits)
+}
+
+fn parse_rlim(arg: &str) -> Result<rlim_t, String> {
+    match arg {
+        "unlimited" | "infinity" => Ok(RLIM_INFINITY),
+        _ => parse_size(arg),
+    }
+}
+
+// The lowest niceness that the child may be given, only root may go lower than ours unless
+// RLIMIT_NICE allows it
+fn lowest_nice() -> i32 {
+    if Uid::effective().is_root() {
+        return -20;
+    }
+
+    let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    // The limit is a ceiling of 20 - niceness, from 1 to 40
+    let ceiling = resource::getrlimit(Resource::RLIMIT_NICE).map_or(0, |(soft, _)| soft.min(40));
+
+    cmp::min(current, 20 - ceiling as i32)
+}
+
+// Parses a list of cpus such as 0,2-3
+fn parse_cpu_set(arg: &str) -> Result<CpuSet, String> {
+    let mut cpu_set = CpuSet::new();
+
+    for range in arg.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+
+        let first: usize = first
+            .parse()
+            .map_err(|_| format!("invalid cpu '{first}'"))?;
+        let last: usize = last.parse().map_err(|_| format!("invalid cpu '{last}'"))?;
+
+        for cpu in first..=last {
+            cpu_set
+                .set(cpu)
+                .map_err(|_| format!("invalid cpu '{cpu}'"))?;
+        }
+    }
+
+    Ok(cpu_set)
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+
+use nix::unistd::{self, Pid, SysconfVar};
+
+// The parts of /proc/<pid>/stat that runar cares about
+pub struct Stat {
+    pub pid: Pid,
+    pub state: char,
+    pub ppid: Pid,
+    pub pgrp: Pid,
+    // Resident memory in bytes
+    pub rss: u64,
+}
+
+pub fn stat(pid: Pid) -> Option<Stat> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+    // The command name is within parentheses and may contain both spaces and parentheses
+    let mut fields = stat[stat.rfind(')')? + 2..].split(' ');
+
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    let pgrp = fields.next()?.parse().ok()?;
+    // rss is the 24th field, counted in pages
+    let rss: u64 = fields.nth(18)?.parse().ok()?;
+
+    Some(Stat {
+        pid,
+        state,
+        ppid: Pid::from_raw(ppid),
+        pgrp: Pid::from_raw(pgrp),
+        rss: rss * page_size(),
+    })
+}
+
+// The value of an environment variable that the process was started with
+pub fn env_var(pid: Pid, name: &str) -> Option<OsString> {
+    let environ = fs::read(format!("/proc/{pid}/environ")).ok()?;
+
+    environ
+        .split(|&b| b == 0)
+        .find_map(|var| var.strip_prefix(name.as_bytes())?.strip_prefix(b"="))
+        .map(|value| OsStr::from_bytes(value).to_os_string())
+}
+
+fn page_size() -> u64 {
+    match unistd::sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(page_size)) => page_size as u64,
+        _ => 4096,
+    }
+}
+
+// Every process currently visible in /proc
+pub fn all() -> Vec<Stat> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter_map(|pid| stat(Pid::from_raw(pid)))
+        .collect()
+}
+
+// Every living process below the given ones, found by following the parent links
+// As runar is a subreaper, the orphans of its children are found here too
+pub fn descendants(pids: &[Pid]) -> Vec<Pid> {
+    let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+
+    for stat in all() {
+        if stat.state != 'Z' {
+            children.entry(stat.ppid).or_default().push(stat.pid);
+        }
+    }
+
+    let mut descendants = Vec::new();
+    let mut parents = pids.to_vec();
+
+    while let Some(parent) = parents.pop() {
+        if let Some(pids) = children.remove(&parent) {
+            descendants.extend(&pids);
+            parents.extend(pids);
+        }
+    }
+
+    descendants
+}
+
+// The resident memory of the processes in a process group, in bytes
+pub fn group_rss(pgrp: Pid) -> u64 {
+    all()
+        .iter()
+        .filter(|stat| stat.pgrp == pgrp)
+        .map(|stat| stat.rss)
+        .sum()
+}
@@ -0,0 +1,582 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::AsFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::Pid;
+
+use crate::notify::{Message, NotifySocket};
+use crate::parse_args::Options;
+
+// When an entry is ready, so that the entries that come after it may start
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Ready {
+    Started,
+    Exit,
+    Port(u16),
+    File(PathBuf),
+    Notify,
+}
+
+// A process of a Procfile and the runar flags it is supervised with
+pub struct Entry {
+    pub name: String,
+    pub flags: Vec<String>,
+    pub after: Vec<String>,
+    pub ready: Ready,
+    pub command: String,
+}
+
+// Reads the entries of a Procfile
+pub fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+    parse(&contents).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+// Parses "name: command" lines, the command may be preceded by runar flags that end with --
+// The flags --after and --ready are for the order of the entries, the rest are passed on
+fn parse(contents: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let lineno = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((name, command)) = line.split_once(':') else {
+            return Err(format!("line {lineno}: expected name: command"));
+        };
+
+        let name = name.trim_end();
+        let valid_name = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid_name) {
+            return Err(format!("line {lineno}: invalid name '{name}'"));
+        }
+
+        if entries.iter().any(|entry| entry.name == name) {
+            return Err(format!("line {lineno}: duplicate name '{name}'"));
+        }
+
+        let command = command.trim();
+
+        // Commands do not start with a dash, flags do
+        let (flags, command) = if command.starts_with('-') {
+            // The -- may also end the line, which is caught as a missing command below
+            let command = format!("{command} ");
+            let Some((flags, command)) = command.split_once(" -- ") else {
+                return Err(format!("line {lineno}: expected -- after the flags"));
+            };
+
+            let flags = split_words(flags).map_err(|e| format!("line {lineno}: {e}"))?;
+            (flags, command.trim().to_string())
+        } else {
+            (Vec::new(), command.to_string())
+        };
+
+        let mut after = Vec::new();
+        let mut ready = Ready::Started;
+        let mut passed_flags = Vec::new();
+        let mut flags = flags.into_iter();
+
+        while let Some(flag) = flags.next() {
+            match flag.as_str() {
+                "--after" => match flags.next() {
+                    Some(name) => after.push(name),
+                    None => return Err(format!("line {lineno}: --after expects a name")),
+                },
+                "--ready" => match flags.next() {
+                    Some(arg) => {
+                        ready = parse_ready(&arg).map_err(|e| format!("line {lineno}: {e}"))?
+                    }
+                    None => return Err(format!("line {lineno}: --ready expects a condition")),
+                },
+                _ => passed_flags.push(flag),
+            }
+        }
+
+        if command.is_empty() {
+            return Err(format!("line {lineno}: expected a command"));
+        }
+
+        entries.push(Entry {
+            name: name.to_string(),
+            flags: passed_flags,
+            after,
+            ready,
+            command,
+        });
+    }
+
+    if entries.is_empty() {
+        return Err("no processes found".to_string());
+    }
+
+    for entry in &entries {
+        for name in &entry.after {
+            if !entries.iter().any(|other| other.name == *name) {
+                return Err(format!(
+                    "{} comes after unknown process '{name}'",
+                    entry.name
+                ));
+            }
+        }
+    }
+
+    // Every entry must be able to start, which is not the case when they wait on each other
+    let mut ordered: Vec<&str> = Vec::new();
+    while ordered.len() < entries.len() {
+        let next = entries.iter().find(|entry| {
+            !ordered.contains(&entry.name.as_str())
+                && entry
+                    .after
+                    .iter()
+                    .all(|name| ordered.contains(&name.as_str()))
+        });
+
+        match next {
+            Some(entry) => ordered.push(&entry.name),
+            None => {
+                let cycle: Vec<&str> = entries
+                    .iter()
+                    .map(|entry| entry.name.as_str())
+                    .filter(|name| !ordered.contains(name))
+                    .collect();
+                return Err(format!("dependency cycle between {}", cycle.join(", ")));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// Parses exit, port:<port>, file:<path> or notify
+fn parse_ready(arg: &str) -> Result<Ready, String> {
+    match arg.split_once(':') {
+        None if arg == "exit" => Ok(Ready::Exit),
+        None if arg == "notify" => Ok(Ready::Notify),
+        Some(("port", port)) => port
+            .parse()
+            .map(Ready::Port)
+            .map_err(|_| format!("invalid port '{port}'")),
+        Some(("file", path)) if !path.is_empty() => Ok(Ready::File(PathBuf::from(path))),
+        _ => Err(format!(
+            "expected exit, port:<port>, file:<path> or notify, got '{arg}'"
+        )),
+    }
+}
+
+// Splits flags into words like a shell would, quotes group words but there are no escapes
+fn split_words(flags: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    for c in flags.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+
+    words.extend(word);
+
+    Ok(words)
+}
+
+// An entry of the Procfile while it is supervised
+struct Process<'a> {
+    entry: &'a Entry,
+    child: Option<Child>,
+    ready: bool,
+    exited: bool,
+    stopped: bool,
+    // Stopped to be started again, after an entry that it comes after restarted
+    held: bool,
+    notify: Option<NotifySocket>,
+}
+
+// Runs every entry of the Procfile under a runar of its own, until all of them have exited
+// Entries are started once the entries they come after are ready, and stopped in reverse
+// An entry that restarts has the entries that come after it stopped and started again
+// An entry that fails, or a signal to us, stops all of them
+pub fn run(path: &Path, opts: &Options) -> io::Result<u8> {
+    let entries = match read(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("<runar> Error: {e}");
+            return Ok(1);
+        }
+    };
+
+    opts.sigmask.thread_block()?;
+    let signalfd = SignalFd::with_flags(&opts.sigmask, SfdFlags::SFD_NONBLOCK)?;
+
+    let width = entries
+        .iter()
+        .map(|entry| entry.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut outputs = Vec::new();
+    let mut processes: Vec<Process> = entries
+        .iter()
+        .map(|entry| Process {
+            entry,
+            child: None,
+            ready: false,
+            exited: false,
+            stopped: false,
+            held: false,
+            notify: None,
+        })
+        .collect();
+
+    let mut exitstatus = 0;
+    let mut stopping = false;
+
+    loop {
+        if stopping {
+            stop(&mut processes, true);
+        } else {
+            stop(&mut processes, false);
+
+            for index in 0..processes.len() {
+                let entry = processes[index].entry;
+                let waiting = entry.after.iter().any(|name| {
+                    !processes
+                        .iter()
+                        .any(|process| process.entry.name == *name && process.ready)
+                });
+
+                if processes[index].child.is_none() && !waiting {
+                    if let Err(e) = start(&mut processes[index], width, &mut outputs, opts) {
+                        eprintln!("<runar> Error: could not run {}: {e}", entry.name);
+                        exitstatus = 1;
+                        stopping = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !processes
+            .iter()
+            .any(|process| process.child.is_some() && !process.exited)
+        {
+            break;
+        }
+
+        // Ports and files are polled until they are there
+        let polling = processes.iter().any(|process| {
+            process.child.is_some()
+                && !process.ready
+                && matches!(process.entry.ready, Ready::Port(_) | Ready::File(_))
+        });
+        let timeout = if polling {
+            PollTimeout::from(100_u8)
+        } else {
+            PollTimeout::NONE
+        };
+
+        let mut pfds = vec![PollFd::new(signalfd.as_fd(), PollFlags::POLLIN)];
+        pfds.extend(
+            processes
+                .iter()
+                .filter_map(|process| process.notify.as_ref())
+                .map(|notify| PollFd::new(notify.as_fd(), PollFlags::POLLIN)),
+        );
+
+        match poll(&mut pfds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some(siginfo) = signalfd.read_signal()? {
+            match Signal::try_from(siginfo.ssi_signo as i32) {
+                Ok(Signal::SIGCHLD) => {
+                    reap(&mut processes, &mut exitstatus, &mut stopping)?;
+                }
+                Ok(signal) if !stopping => {
+                    if opts.verbose {
+                        println!("<runar> got {signal}, stopping all processes");
+                    }
+
+                    stopping = true;
+                }
+                _ => (),
+            }
+        }
+
+        for index in 0..processes.len() {
+            let messages = match &processes[index].notify {
+                Some(notify) => notify.recv(),
+                None => continue,
+            };
+
+            let entry = processes[index].entry;
+            for message in messages {
+                match message {
+                    Message::Ready if entry.ready == Ready::Notify => {
+                        processes[index].ready = true;
+
+                        if opts.verbose {
+                            println!("<runar> {} is ready", entry.name);
+                        }
+                    }
+                    Message::Restarted if !stopping => {
+                        processes[index].ready = entry.ready == Ready::Started;
+
+                        if processes
+                            .iter()
+                            .any(|other| other.entry.after.contains(&entry.name))
+                        {
+                            eprintln!(
+                                "<runar> {} restarted, restarting the processes that come after it",
+                                entry.name
+                            );
+                            hold(&mut processes, &entry.name);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for process in &mut processes {
+            if process.child.is_none() || process.ready || process.exited {
+                continue;
+            }
+
+            process.ready = match &process.entry.ready {
+                Ready::Started | Ready::Exit | Ready::Notify => false,
+                Ready::Port(port) => TcpStream::connect(("localhost", *port)).is_ok(),
+                Ready::File(path) => path.exists(),
+            };
+
+            if process.ready && opts.verbose {
+                println!("<runar> {} is ready", process.entry.name);
+            }
+        }
+    }
+
+    // The output of the entries is written out before we exit
+    for output in outputs {
+        let _ = output.join();
+    }
+
+    Ok(exitstatus)
+}
+
+// Starts a runar for the entry, which runs the command with sh
+fn start(
+    process: &mut Process,
+    width: usize,
+    outputs: &mut Vec<JoinHandle<()>>,
+    opts: &Options,
+) -> io::Result<()> {
+    let entry = process.entry;
+    let mut command = Command::new("/proc/self/exe");
+    command
+        .args(&entry.flags)
+        .args(["--", "sh", "-c", &entry.command])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Signals from the terminal are for us, we decide how the entries are stopped
+        .process_group(0);
+
+    // The socket is kept for the next start, its runar tells us when it is ready or restarted
+    if process.notify.is_none() {
+        process.notify = Some(NotifySocket::bind(&entry.name, None, None)?);
+    }
+    if let Some(notify) = &process.notify {
+        command.env("NOTIFY_SOCKET", notify.path());
+    }
+
+    let sigmask = opts.sigmask;
+    unsafe {
+        command.pre_exec(move || {
+            sigmask.thread_unblock()?;
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    if opts.verbose {
+        println!("<runar> {} spawned with pid {}", entry.name, child.id());
+    }
+
+    let prefix = format!("{:width$} | ", entry.name);
+    if let Some(stdout) = child.stdout.take() {
+        outputs.push(prefix_output(stdout, prefix.clone(), io::stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        outputs.push(prefix_output(stderr, prefix, io::stderr));
+    }
+
+    process.child = Some(child);
+    process.ready = entry.ready == Ready::Started;
+
+    Ok(())
+}
+
+// Reaps the entries that have exited, all entries are stopped once one of them fails
+fn reap(processes: &mut [Process], exitstatus: &mut u8, stopping: &mut bool) -> io::Result<()> {
+    for index in 0..processes.len() {
+        let process = &mut processes[index];
+
+        let status = match &mut process.child {
+            Some(child) if !process.exited => child.try_wait()?,
+            _ => None,
+        };
+
+        let Some(status) = status else {
+            continue;
+        };
+
+        // It is started again once the entries it comes after are ready
+        if process.held && !*stopping {
+            process.child = None;
+            process.ready = false;
+            process.stopped = false;
+            process.held = false;
+            continue;
+        }
+
+        let code = exit_code(status);
+        process.exited = true;
+
+        if code != 0 && *exitstatus == 0 {
+            *exitstatus = code;
+        }
+
+        if code == 0 && process.entry.ready == Ready::Exit {
+            process.ready = true;
+        }
+
+        if *stopping {
+            continue;
+        }
+
+        let name = &processes[index].entry.name;
+        let waited_on = processes
+            .iter()
+            .any(|other| other.entry.after.contains(name));
+
+        if code != 0 {
+            eprintln!("<runar> {name} exited with status {code}, stopping all processes");
+            *stopping = true;
+        } else if !processes[index].ready && waited_on {
+            // The entries that come after it would wait forever
+            eprintln!("<runar> {name} exited before it was ready, stopping all processes");
+            *exitstatus = 1;
+            *stopping = true;
+        }
+    }
+
+    Ok(())
+}
+
+// Asks the runar of every entry, or only of the held ones, to stop its process, it exits once it
+// has done so
+// Entries that others come after are only stopped once the others have exited
+fn stop(processes: &mut [Process], all: bool) {
+    for index in 0..processes.len() {
+        let process = &processes[index];
+
+        let Some(child) = &process.child else {
+            continue;
+        };
+
+        if (all || process.held)
+            && !process.exited
+            && !process.stopped
+            && !is_needed(processes, &process.entry.name)
+        {
+            let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+            processes[index].stopped = true;
+        }
+    }
+}
+
+// Holds the entries that come after the named entry, directly or through other entries
+// Running entries are stopped, those that have exited are reset, both are started again
+fn hold(processes: &mut [Process], name: &str) {
+    for index in 0..processes.len() {
+        let entry = processes[index].entry;
+        if !entry.after.iter().any(|after| after == name) {
+            continue;
+        }
+
+        let process = &mut processes[index];
+        if process.exited {
+            process.child = None;
+            process.exited = false;
+        } else if process.child.is_some() {
+            process.held = true;
+        }
+        process.ready = false;
+
+        hold(processes, &entry.name);
+    }
+}
+
+// Whether a running entry comes after the named entry, directly or through other entries
+fn is_needed(processes: &[Process], name: &str) -> bool {
+    processes.iter().any(|other| {
+        other.entry.after.iter().any(|after| after == name)
+            && ((other.child.is_some() && !other.exited) || is_needed(processes, &other.entry.name))
+    })
+}
+
+// The exit status of a runar, like a shell would give it
+fn exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => 128 + signal as u8,
+        (None, None) => 1,
+    }
+}
+
+// Copies the output of an entry line by line, each line prefixed with the name of the entry
+fn prefix_output<R, W>(output: R, prefix: String, out: fn() -> W) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: Write + 'static,
+{
+    thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+
+            let mut buf = prefix.clone().into_bytes();
+            buf.extend(line);
+            buf.push(b'\n');
+
+            // Whole lines are written at once, so the lines of entries are not mixed up
+            let _ = out().write_all(&buf);
+        }
+    })
+}
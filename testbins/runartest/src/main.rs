@@ -1,11 +1,16 @@
 use std::env::Args;
 use std::io::BufRead;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use std::{env, io, process, thread};
+use std::{env, fs, io, process, thread};
 
 use nix::sys::signal::{signal, SigHandler, Signal};
 
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
 // TODO refactor runartest to take flag arguments for name/wait/pgrp instead
 
 fn main() {
@@ -48,10 +53,102 @@ fn main() {
             // sleep so that child has a chance to start
             thread::sleep(Duration::from_millis(10));
         }
+        Some("orphans") => {
+            // spawns several children that exit at the same time, after we have exited
+            let childname = args.next().unwrap();
+            for _ in 0..5 {
+                Command::new(&runartest)
+                    .args([&childname, "nap"])
+                    .spawn()
+                    .expect("Could not create child");
+            }
+        }
+        Some("orphanerror") => {
+            // spawns several children that exit right away, and exits with an error alongside them
+            let childname = args.next().unwrap();
+            for _ in 0..5 {
+                Command::new(&runartest)
+                    .args([&childname, "success"])
+                    .stdout(process::Stdio::null())
+                    .spawn()
+                    .expect("Could not create child");
+            }
+            eprintln!("err {}", name);
+            process::exit(13);
+        }
+        Some("nap") => {
+            thread::sleep(Duration::from_millis(100));
+        }
+        Some("leak") => {
+            // holds on to 64M of memory that has been written to, so that it is resident
+            let memory = vec![1u8; 64 << 20];
+            thread::sleep(Duration::from_millis(10_000));
+            drop(memory);
+        }
+        Some("work") => {
+            thread::sleep(Duration::from_millis(500));
+        }
+        Some("notify") => {
+            // tells runar that we are ready, then goes quiet
+            let socket = UnixDatagram::unbound().unwrap();
+            let path = env::var("NOTIFY_SOCKET").unwrap();
+            socket.send_to(b"READY=1\nSTATUS=serving", &path).unwrap();
+            socket.send_to(b"WATCHDOG=1", &path).unwrap();
+            thread::sleep(Duration::from_millis(10_000));
+        }
+        Some("linger") => {
+            // spawns a child runartest, and takes its time to exit once it gets SIGTERM
+            unsafe {
+                signal(Signal::SIGTERM, SigHandler::Handler(on_sigterm)).unwrap();
+            }
+            spawn_child(runartest, args, false);
+            while !TERMINATED.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            thread::sleep(Duration::from_millis(300));
+        }
+        Some("escape") => {
+            // spawns a child runartest in a process group of its own and exits
+            let mut command = Command::new(runartest);
+            command.args(args).process_group(0);
+            command.spawn().expect("Could not create child");
+            thread::sleep(Duration::from_millis(10));
+        }
         Some("waitchild") => {
             // spawns a child runartest and waits for it
             spawn_child(runartest, args, true);
         }
+        Some("pwd") => {
+            println!("{}", env::current_dir().unwrap().display());
+        }
+        Some("env") => {
+            let key = args.next().unwrap();
+            match env::var(key) {
+                Ok(value) => println!("{}", value),
+                Err(_) => println!("unset"),
+            }
+        }
+        Some("limits") => {
+            // prints the soft and hard limit on open files, followed by niceness and cpus
+            let limits = fs::read_to_string("/proc/self/limits").unwrap();
+            let nofile = limits
+                .lines()
+                .find(|line| line.starts_with("Max open files"))
+                .unwrap();
+            let nofile: Vec<&str> = nofile.split_whitespace().collect();
+            println!("nofile {} {}", nofile[3], nofile[4]);
+
+            let stat = fs::read_to_string("/proc/self/stat").unwrap();
+            let stat: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+            println!("nice {}", stat[16]);
+
+            let status = fs::read_to_string("/proc/self/status").unwrap();
+            let cpus = status
+                .lines()
+                .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+                .unwrap();
+            println!("cpus {}", cpus.trim());
+        }
         Some("cat") => {
             let stdin = io::stdin();
             let mut lines = stdin.lock().lines();
@@ -92,3 +189,7 @@ fn spawn_child(runartest: String, args: Args, wait: bool) {
         }
     }
 }
+
+extern "C" fn on_sigterm(_: i32) {
+    TERMINATED.store(true, Ordering::SeqCst);
+}
@@ -6,11 +6,15 @@ extern crate test_binary;
 
 mod integration {
     use std::ffi::OsString;
+    use std::os::unix::net::UnixDatagram;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
     use std::process::Child;
     use std::process::Stdio;
-    use std::sync::LazyLock;
+    use std::sync::OnceLock;
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+    use std::{env, fs};
 
     use assert_cmd::assert::Assert;
     use assert_cmd::cargo::cargo_bin;
@@ -22,15 +26,16 @@ mod integration {
 
     use nix::sys::signal::kill;
     use nix::sys::signal::Signal;
-    use nix::unistd::Pid;
+    use nix::unistd::{Pid, Uid};
 
     use test_binary::build_test_binary;
 
-    static INIT: LazyLock<OsString> =
-        LazyLock::new(|| build_test_binary("runartest", "testbins").unwrap());
+    static INIT: OnceLock<OsString> = OnceLock::new();
 
     fn testprog() -> &'static str {
-        INIT.to_str().unwrap()
+        INIT.get_or_init(|| build_test_binary("runartest", "testbins").unwrap())
+            .to_str()
+            .unwrap()
     }
 
     fn run_runar(args: Vec<&str>) -> Child {
@@ -42,6 +47,69 @@ mod integration {
             .unwrap()
     }
 
+    // Counts the zombie processes that are waiting to be reaped by pid
+    fn zombie_children(pid: u32) -> usize {
+        let mut zombies = 0;
+
+        for entry in fs::read_dir("/proc").unwrap() {
+            let Ok(stat) = fs::read_to_string(entry.unwrap().path().join("stat")) else {
+                continue;
+            };
+
+            // The fields after the command name are "state ppid ..."
+            let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+
+            if fields[0] == "Z" && fields[1] == pid.to_string() {
+                zombies += 1;
+            }
+        }
+
+        zombies
+    }
+
+    // Counts the running processes started with name as their first argument
+    fn processes_named(name: &str) -> usize {
+        pids_named(name).len()
+    }
+
+    // The running processes started with name as their first argument
+    fn pids_named(name: &str) -> Vec<Pid> {
+        let mut pids = Vec::new();
+
+        for entry in fs::read_dir("/proc").unwrap() {
+            let path = entry.unwrap().path();
+            let Ok(cmdline) = fs::read(path.join("cmdline")) else {
+                continue;
+            };
+            let Ok(stat) = fs::read_to_string(path.join("stat")) else {
+                continue;
+            };
+
+            let state = &stat[stat.rfind(')').unwrap() + 2..][..1];
+            let is_named = cmdline.split(|&b| b == 0).nth(1) == Some(name.as_bytes());
+
+            if is_named && state != "Z" {
+                let pid = path.file_name().unwrap().to_str().unwrap();
+                pids.push(Pid::from_raw(pid.parse().unwrap()));
+            }
+        }
+
+        pids
+    }
+
+    // Creates a cgroup for runar in the cgroup v2 hierarchy, if we are allowed to
+    fn test_cgroup(name: &str) -> Option<PathBuf> {
+        let mounts = fs::read_to_string("/proc/self/mounts").unwrap();
+        let mount = mounts
+            .lines()
+            .map(|line| line.split(' ').collect::<Vec<_>>())
+            .find(|fields| fields[2] == "cgroup2")?[1];
+
+        let dir = Path::new(mount).join(format!("{name}-{}", std::process::id()));
+        fs::create_dir(&dir).ok()?;
+        Some(dir)
+    }
+
     fn delayed_write_file(millis: u64, tmp_file: ChildPath) {
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(millis));
@@ -203,200 +271,1633 @@ mod integration {
     }
 
     #[test]
-    fn file_watch() {
-        let tmp_dir = TempDir::new().unwrap();
-        let tmp_file = tmp_dir.child("file");
-        tmp_file.touch().unwrap();
-        let file = tmp_file.to_str().unwrap();
+    fn restart_on_error_with_backoff() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-S",
+                "--backoff",
+                "100ms,1s,4",
+                "--",
+                testprog(),
+                "foo",
+                "error",
+            ])
+            .timeout(Duration::from_millis(800))
+            .assert();
 
-        let runar = run_runar(vec!["-f", file, "--", testprog(), "foo", "sleep"]);
+        // runar starts runartest
+        // runartest errors
+        // runar restarts runartest after 100ms
+        // runartest errors
+        // runar restarts runartest after 400ms
+        // runartest errors
+        // runar gets interrupted while waiting 1s
+        assert
+            .stdout("start foo\nstart foo\nstart foo\n")
+            .stderr("err foo\nerr foo\nerr foo\n")
+            .interrupted();
+    }
 
-        delayed_write_file(200, tmp_file);
-        delayed_sigterm(500, runar.id() as i32);
+    #[test]
+    fn invalid_backoff_fails() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--backoff", "1s,100ms", "--", testprog(), "foo", "success"])
+            .timeout(Duration::from_millis(200))
+            .assert();
 
-        let output = runar.wait_with_output().unwrap();
-        let assert = Assert::new(output);
+        assert.failure();
+    }
+
+    #[test]
+    fn too_large_duration_fails() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "--timeout",
+                "18446744073709551615h",
+                "--",
+                testprog(),
+                "foo",
+                "success",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        assert.failure().stderr(
+            "<runar> Error: failed to parse '18446744073709551615h': duration \
+             '18446744073709551615h' is too large\n",
+        );
+    }
+
+    #[test]
+    fn restart_on_error_with_max_restarts() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-S",
+                "--max-restarts",
+                "2",
+                "--",
+                testprog(),
+                "foo",
+                "error",
+            ])
+            .timeout(Duration::from_millis(1000))
+            .assert();
 
         // runar starts runartest
-        // runartest sleeps
-        // file is written
-        // runar restarts runartest
-        // runartest sleeps
-        // runar gets sigterm
-        // runar sends sigterm to runartest
-        assert.stdout("start foo\nstart foo\n").stderr("");
+        // runartest errors twice and is restarted each time
+        // runartest errors a third time
+        // runar gives up and exits with runartests status 13
+        assert
+            .stdout("start foo\nstart foo\nstart foo\n")
+            .stderr(format!(
+                "err foo\nerr foo\nerr foo\n\
+                <runar> {} was restarted 2 times within 60s, giving up\n\
+                <runar> recent exit statuses: 13, 13, 13\n",
+                testprog()
+            ))
+            .code(13);
     }
 
     #[test]
-    fn recursive_file_watch() {
-        let tmp_dir = TempDir::new().unwrap();
-        let dir = tmp_dir.to_str().unwrap();
-        let tmp_file = tmp_dir.child("deep/file");
-        tmp_file.touch().unwrap();
+    fn timeout_exits_with_124() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-E", "--timeout", "200ms", "--", testprog(), "foo", "sleep"])
+            .timeout(Duration::from_millis(2000))
+            .assert();
 
-        let runar = run_runar(vec!["-rf", dir, "--", testprog(), "foo", "sleep"]);
+        // runar starts runartest, which sleeps for longer than the timeout
+        // runar terminates runartest and exits with the timed out status
+        assert
+            .stdout("start foo\n")
+            .stderr(format!("<runar> {} timed out after 200ms\n", testprog()))
+            .code(124);
+    }
 
-        delayed_write_file(200, tmp_file);
-        delayed_sigterm(500, runar.id() as i32);
+    #[test]
+    fn every_interval() {
+        let runar = run_runar(vec!["--every", "200ms", "--", testprog(), "foo", "success"]);
+
+        delayed_sigterm(700, runar.id() as i32);
 
         let output = runar.wait_with_output().unwrap();
-        let assert = Assert::new(output);
+        let stdout = String::from_utf8(output.stdout).unwrap();
 
-        // runar starts runartest
-        // runartest sleeps
-        // file is written
-        // runar restarts runartest
-        // runartest sleeps
-        // runar gets sigterm
-        // runar sends sigterm to runartest
-        assert.stdout("start foo\nstart foo\n").stderr("");
+        // runar starts runartest, which exits at once
+        // runar runs runartest again every 200ms until it gets sigterm
+        assert_eq!(stdout.matches("start foo\nend foo\n").count(), 4);
     }
 
     #[test]
-    fn uninterruptible_cmd() {
-        let tmp_dir = TempDir::new().unwrap();
-        let tmp_file = tmp_dir.child("file");
-        tmp_file.touch().unwrap();
-        let file = tmp_file.to_str().unwrap();
+    fn every_interval_overlap() {
+        let runar = run_runar(vec!["--every", "200ms", "--", testprog(), "foo", "sleep"]);
 
-        let runar = run_runar(vec!["-Ek10", "-f", file, "--", testprog(), "foo", "hang"]);
+        delayed_sigterm(700, runar.id() as i32);
 
-        delayed_write_file(200, tmp_file);
-        delayed_sigterm(500, runar.id() as i32);
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runartest is still sleeping every time the interval passes, so the runs are skipped
+        assert.stdout("start foo\n").code(143);
+
+        let runar = run_runar(vec![
+            "--every",
+            "200ms",
+            "--overlap",
+            "restart",
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        delayed_sigterm(700, runar.id() as i32);
 
         let output = runar.wait_with_output().unwrap();
         let assert = Assert::new(output);
 
-        // runar starts runartest
-        // runartest hangs
-        // file is written
-        // runar attempts to restart runartest
-        // runartest gets sigterm
-        // runartest gets sigkill
-        // runar restarts runartest
-        // runar gets sigterm
-        // runar sends sigterm and then sigkill to runartest
-        assert.stdout("start foo\nstart foo\n").stderr("");
+        // runartest is restarted every time the interval passes
+        assert.stdout("start foo\n".repeat(4)).code(143);
     }
 
     #[test]
-    fn multiple_writes() {
+    fn notify_ready_forwarded() {
         let tmp_dir = TempDir::new().unwrap();
-        let dir = tmp_dir.to_str().unwrap();
-
-        let tmp_file_1 = tmp_dir.child("file1");
-        tmp_file_1.touch().unwrap();
-
-        let tmp_file_2 = tmp_dir.child("file2");
-        tmp_file_2.touch().unwrap();
-
-        let tmp_file_3 = tmp_dir.child("file3");
-        tmp_file_3.touch().unwrap();
+        let socket_path = tmp_dir.child("notify.sock");
+        let socket = UnixDatagram::bind(socket_path.path()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(2000)))
+            .unwrap();
+
+        let runar = std::process::Command::new(cargo_bin(env!("CARGO_PKG_NAME")))
+            .args(["--notify", "--", testprog(), "foo", "notify"])
+            .env("NOTIFY_SOCKET", socket_path.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
 
-        let runar = run_runar(vec!["-rf", dir, "--", testprog(), "foo", "sleep"]);
+        // runartest sends READY=1 and STATUS to runar, which passes them on to its own socket
+        let mut buf = [0; 64];
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            let len = socket.recv(&mut buf).unwrap();
+            messages.push(buf[..len].to_vec());
+        }
+        messages.sort();
+        assert_eq!(messages, [b"READY=1".to_vec(), b"STATUS=serving".to_vec()]);
 
-        delayed_write_file(200, tmp_file_1);
-        delayed_write_file(230, tmp_file_2);
-        delayed_write_file(260, tmp_file_3);
-        delayed_sigterm(500, runar.id() as i32);
+        kill(Pid::from_raw(runar.id() as i32), Signal::SIGTERM).unwrap();
 
         let output = runar.wait_with_output().unwrap();
         let assert = Assert::new(output);
+        assert.stdout("start foo\n").code(143);
+    }
 
-        // runar starts runartest
-        // runartest sleeps
-        // file1 is written
-        // runar stops runartest
-        // file2 and file3 are written
-        // runar clears filewrites from buffer
-        // runar starts runartest
-        assert.stdout("start foo\nstart foo\n").stderr("");
+    #[test]
+    fn notify_socket_owner() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--notify",
+                "--user",
+                "nobody",
+                "--",
+                "sh",
+                "-c",
+                "stat -c '%U %a' \"$NOTIFY_SOCKET\"",
+            ])
+            .timeout(Duration::from_millis(500))
+            .assert();
+
+        // The socket is only open to the user the child runs as
+        if Uid::effective().is_root() {
+            assert.stdout("nobody 600\n").stderr("").success();
+        } else {
+            assert
+                .stdout("")
+                .stderr("<runar> Error: --user and --group require runar to run as root\n")
+                .failure();
+        }
     }
 
     #[test]
-    fn file_watch_with_child_sleep() {
-        let tmp_dir = TempDir::new().unwrap();
-        let tmp_file = tmp_dir.child("file");
-        tmp_file.touch().unwrap();
-        let file = tmp_file.to_str().unwrap();
+    fn notify_watchdog() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-E",
+                "--notify",
+                "--watchdog",
+                "300ms",
+                "--",
+                testprog(),
+                "foo",
+                "notify",
+            ])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // runartest sends WATCHDOG=1 once and then goes quiet
+        // runar stops runartest once the watchdog runs out and exits with its status
+        assert
+            .stdout("start foo\n")
+            .stderr(format!(
+                "<runar> {} did not send WATCHDOG=1 within 300ms\n",
+                testprog()
+            ))
+            .code(143);
+    }
 
+    #[test]
+    fn liveness() {
         let runar = run_runar(vec![
-            "-k10",
-            "-f",
-            file,
+            "--liveness",
+            "300ms",
             "--",
             testprog(),
             "foo",
-            "waitchild",
-            "bar",
             "sleep",
         ]);
 
-        delayed_write_file(200, tmp_file);
-        delayed_sigterm(500, runar.id() as i32);
+        delayed_sigterm(700, runar.id() as i32);
 
         let output = runar.wait_with_output().unwrap();
-        let assert = Assert::new(output);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
 
-        // runar starts runartest foo
-        // foo starts child bar
-        // foo waits on bar
-        // bar sleeps
-        // file is written
-        // runar stops foo
-        // runar cleans up bar
-        // runar restarts foo
+        // runartest goes quiet after it starts, so runar restarts it once 300ms have passed
+        assert_eq!(stdout, "start foo\nstart foo\n");
+        assert_eq!(
+            stderr,
+            format!(
+                "<runar> {} produced no output for 300ms, restarting\n",
+                testprog()
+            )
+        );
+
+        let runar = run_runar(vec!["--liveness", "300ms", "--", testprog(), "foo", "run"]);
+
+        delayed_sigterm(700, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        // runartest writes output every 100ms and is left running
+        assert_eq!(stdout.matches("start foo\n").count(), 1);
+        assert!(stdout.contains("a\n"));
+        assert!(output.stderr.is_empty());
+    }
+
+    #[test]
+    fn liveness_with_max_restarts() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "--liveness",
+                "100ms",
+                "--max-restarts",
+                "2",
+                "--",
+                testprog(),
+                "foo",
+                "sleep",
+            ])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // Restarts because of the liveness check count towards --max-restarts like exits do
+        let stalled = format!(
+            "<runar> {} produced no output for 100ms, restarting\n",
+            testprog()
+        );
         assert
-            .stdout("start foo\nstart bar\nstart foo\nstart bar\n")
-            .stderr("");
+            .code(143)
+            .stdout("start foo\nstart foo\nstart foo\n")
+            .stderr(format!(
+                "{stalled}{stalled}{stalled}\
+                <runar> {} was restarted 2 times within 60s, giving up\n\
+                <runar> recent exit statuses: 143, 143, 143\n",
+                testprog()
+            ));
     }
 
     #[test]
-    fn file_watch_with_child_hang() {
-        let tmp_dir = TempDir::new().unwrap();
-        let tmp_file = tmp_dir.child("file");
-        tmp_file.touch().unwrap();
-        let file = tmp_file.to_str().unwrap();
+    fn health_check() {
+        let runar = run_runar(vec![
+            "--health-cmd",
+            "exit 3",
+            "--health-interval",
+            "200ms",
+            "--health-retries",
+            "2",
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        delayed_sigterm(600, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        // The health check fails every 200ms, runar restarts runartest after the second failure
+        assert_eq!(stdout, "start foo\nstart foo\n");
+        assert_eq!(
+            stderr,
+            format!(
+                "<runar> health check failed with status 3\n\
+                 <runar> health check failed with status 3\n\
+                 <runar> {} failed 2 health checks in a row, restarting\n",
+                testprog()
+            )
+        );
 
         let runar = run_runar(vec![
-            "-k10",
-            "-f",
-            file,
+            "--health-cmd",
+            "true",
+            "--health-interval",
+            "100ms",
             "--",
             testprog(),
             "foo",
-            "waitchild",
-            "bar",
-            "hang",
+            "sleep",
         ]);
 
-        delayed_write_file(200, tmp_file);
-        delayed_sigterm(500, runar.id() as i32);
+        delayed_sigterm(450, runar.id() as i32);
 
         let output = runar.wait_with_output().unwrap();
         let assert = Assert::new(output);
 
-        // runar starts runartest foo
-        // foo starts child bar
-        // foo waits on bar
-        // bar hangs
-        // file is written
-        // runar stops foo
-        // runar cleans up bar (by killing)
-        // runar restarts foo
-        assert
-            .stdout("start foo\nstart bar\nstart foo\nstart bar\n")
-            .stderr("");
+        // The health check succeeds, runartest is left running
+        assert.stdout("start foo\n").stderr("").code(143);
     }
 
     #[test]
-    fn grandchild_cleanup() {
-        let runar = run_runar(vec!["-E", "--", testprog(), "foo", "child", "bar", "sleep"]);
+    fn max_rss() {
+        let runar = run_runar(vec!["--max-rss", "32M", "--", testprog(), "foo", "leak"]);
 
-        delayed_sigterm(500, runar.id() as i32);
+        delayed_sigterm(1500, runar.id() as i32);
 
         let output = runar.wait_with_output().unwrap();
-        let assert = Assert::new(output);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        // runartest holds 64M, runar restarts it at the first check after a second
+        assert_eq!(stdout, "start foo\nstart foo\n");
+        assert!(stderr.contains("of memory, more than 32.0M, restarting\n"));
+
+        let runar = run_runar(vec!["--max-rss", "1G", "--", testprog(), "foo", "leak"]);
+
+        delayed_sigterm(1500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runartest stays below the limit and is left running
+        assert.stdout("start foo\n").stderr("").code(143);
+    }
+
+    #[test]
+    fn restart_on_output() {
+        let runar = run_runar(vec![
+            "--restart-on-output",
+            "^a$",
+            "--",
+            testprog(),
+            "foo",
+            "run",
+        ]);
+
+        delayed_sigterm(350, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        // runartest writes its first line after 100ms, which makes runar restart it
+        assert_eq!(stdout, "start foo\na\nstart foo\na\n");
+        assert_eq!(
+            stderr,
+            format!(
+                "<runar> {0} wrote a line matching --restart-on-output, restarting\n\
+                 <runar> {0} wrote a line matching --restart-on-output, restarting\n",
+                testprog()
+            )
+        );
+    }
+
+    #[test]
+    fn exit_on_output() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-S",
+                "--exit-on-output",
+                "^err",
+                "--",
+                testprog(),
+                "foo",
+                "error",
+            ])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // runartest writes to stderr and exits, runar exits instead of restarting it
+        assert.stdout("start foo\n").stderr(format!(
+            "err foo\n<runar> {} wrote a line matching --exit-on-output, exiting\n",
+            testprog()
+        ));
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "--exit-on-output",
+                "FATAL",
+                "--",
+                "sh",
+                "-c",
+                "echo FATAL; sleep 10",
+            ])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // sh is still running after the line, runar stops it and exits with its status
+        assert
+            .stdout("FATAL\n")
+            .stderr("<runar> sh wrote a line matching --exit-on-output, exiting\n")
+            .code(143);
+    }
+
+    #[test]
+    fn decorated_output() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-s",
+                "--max-restarts",
+                "1",
+                "--run-numbers",
+                "--mark-stderr",
+                "--",
+            ])
+            .args(["sh", "-c", "echo out; printf 'err' >&2"])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // Each line is prefixed with the run that wrote it, the unterminated line is ended by the
+        // decoration or message that comes after it
+        assert
+            .stdout("#1 | out\n#2 | out\n")
+            .stderr(
+                "#1 err | err\n\
+                 #2 err | err\n\
+                 <runar> sh was restarted 1 times within 60s, giving up\n\
+                 <runar> recent exit statuses: 0, 0\n",
+            )
+            .code(0);
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--timestamps", "--", testprog(), "foo", "success"])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        let output = assert.get_output();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        // The time of day looks like 13:37:00.123
+        for (line, expected) in stdout.lines().zip(["start foo", "end foo"]) {
+            let (time, line) = line.split_once(" | ").unwrap();
+            assert_eq!(time.len(), 12);
+            assert_eq!(time.matches(':').count(), 2);
+            assert_eq!(line, expected);
+        }
+        assert_eq!(stdout.lines().count(), 2);
+    }
+
+    #[test]
+    fn partial_lines_passed_through() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-e", "--mark-stderr", "--"])
+            .args([
+                "sh",
+                "-c",
+                "printf out; printf 'e' >&2; sleep 0.1; printf 'rr' >&2",
+            ])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // The output is passed on as it is, the parts of a line are decorated once
+        // The line on stdout is ended once a decorated line starts on stderr
+        assert.stdout("out\n").stderr("err | err").code(0);
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-e", "--run-numbers", "--mark-stderr", "--"])
+            .args(["sh", "-c", "printf out; sleep 0.1; echo err >&2"])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // The line left open on stdout is ended before a decorated line on stderr
+        assert.stdout("#1 | out\n").stderr("#1 err | err\n").code(0);
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-E", "--timeout", "300ms", "--mark-stderr", "--"])
+            .args(["sh", "-c", "printf 'password: '; sleep 5"])
+            .timeout(Duration::from_millis(2000))
+            .assert();
+
+        // The prompt is shown before its line ends, which runar ends for its message
+        assert
+            .stdout("password: \n")
+            .stderr("<runar> sh timed out after 300ms\n")
+            .code(124);
+    }
+
+    #[test]
+    fn timeout_with_continuous_output() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-E", "--timeout", "500ms", "--run-numbers", "--", "yes"])
+            .timeout(Duration::from_millis(3000))
+            .assert();
+
+        // yes never stops writing, the timeout still stops it
+        assert
+            .stderr("<runar> yes timed out after 500ms\n")
+            .code(124);
+    }
+
+    #[test]
+    fn exit_status_on_sigterm() {
+        let runar = run_runar(vec!["--", testprog(), "foo", "sleep"]);
+
+        delayed_sigterm(200, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest sleeps
+        // runar gets sigterm
+        // runar sends sigterm to runartest
+        // runar exits with the status of runartest, 128 + SIGTERM
+        assert.stdout("start foo\n").stderr("").code(143);
+    }
+
+    #[test]
+    fn exit_status_on_sigterm_after_exit() {
+        let runar = run_runar(vec!["--", testprog(), "foo", "error"]);
+
+        delayed_sigterm(200, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest exits with status 13
+        // runar gets sigterm
+        // runar exits with the status of the last run of runartest
+        assert.stdout("start foo\n").stderr("err foo\n").code(13);
+    }
+
+    #[test]
+    fn reraise_sigterm() {
+        let runar = run_runar(vec![
+            "--reraise",
+            "--notify",
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+        let socket = env::temp_dir().join(format!("runar-{}-notify.sock", runar.id()));
+
+        delayed_sigterm(200, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let signal = output.status.signal();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest sleeps
+        // runar gets sigterm
+        // runar sends sigterm to runartest
+        // runar terminates itself with sigterm
+        assert.stdout("start foo\n").stderr("").interrupted();
+        assert_eq!(signal, Some(Signal::SIGTERM as i32));
+        // Its notify socket is removed before that
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn chdir() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path().canonicalize().unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-e", "--chdir", dir, "--", testprog(), "foo", "pwd"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest in dir
+        // runartest prints its working directory
+        assert
+            .stdout(format!("start foo\n{dir}\nend foo\n"))
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn missing_chdir_fails() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--chdir", "/nonexistent", "--", testprog(), "foo", "pwd"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        assert
+            .stdout("")
+            .stderr("<runar> Error: --chdir /nonexistent: No such file or directory (os error 2)\n")
+            .failure();
+    }
+
+    #[test]
+    fn command_not_found() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-E", "--", "/nonexistent/command"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // A command that cannot be run exits like it would in a shell
+        assert
+            .stdout("")
+            .stderr(
+                "<runar> Error: could not run /nonexistent/command: \
+                No such file or directory (os error 2)\n",
+            )
+            .code(127);
+    }
+
+    #[test]
+    fn user_and_group() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--user",
+                "nobody",
+                "--group",
+                "daemon",
+                "--",
+                "sh",
+                "-c",
+                "id -un; id -gn; echo $USER",
+            ])
+            .timeout(Duration::from_millis(500))
+            .assert();
+
+        // Only root may change the identity of the child
+        if Uid::effective().is_root() {
+            assert
+                .stdout("nobody\ndaemon\nnobody\n")
+                .stderr("")
+                .success();
+        } else {
+            assert
+                .stdout("")
+                .stderr("<runar> Error: --user and --group require runar to run as root\n")
+                .failure();
+        }
+    }
+
+    #[test]
+    fn env() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--env",
+                "FOO=bar=baz",
+                "--",
+                testprog(),
+                "foo",
+                "env",
+                "FOO",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest with FOO set
+        // runartest prints FOO
+        assert
+            .stdout("start foo\nbar=baz\nend foo\n")
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn env_clear() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .env("FOO", "bar")
+            .args(["-e", "--env-clear", "--", testprog(), "foo", "env", "FOO"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest with an empty environment
+        // runartest prints FOO
+        assert
+            .stdout("start foo\nunset\nend foo\n")
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn env_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child(".env");
+        tmp_file
+            .write_str(concat!(
+                "# comment\n",
+                "export FOO=\"bar\\tbaz\" # comment\n",
+                "BAR='multiple\n",
+                "lines'\n",
+            ))
+            .unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--env-file",
+                file,
+                "--",
+                testprog(),
+                "foo",
+                "env",
+                "FOO",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest with the variables from the env file
+        // runartest prints FOO
+        assert
+            .stdout("start foo\nbar\tbaz\nend foo\n")
+            .stderr("")
+            .success();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--env-file",
+                file,
+                "--",
+                testprog(),
+                "foo",
+                "env",
+                "BAR",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest with the variables from the env file
+        // runartest prints BAR
+        assert
+            .stdout("start foo\nmultiple\nlines\nend foo\n")
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn env_file_values() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child(".env");
+        tmp_file
+            .write_str(concat!(
+                "\t# indented comment\n",
+                "A=plain value\t# comment after a tab\n",
+                "B=no#comment\n",
+                "C= # only a comment\n",
+                "D=\"escaped \\\"quotes\\\" \\\\ and\\nlines\" # comment\n",
+                "E='single $quoted \\n literal'\n",
+                "export\tF=exported with a tab\n",
+                "G=\"hash # within quotes\"\n",
+            ))
+            .unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--env-file",
+                file,
+                "--",
+                "sh",
+                "-c",
+                "printf '[%s]\\n' \"$A\" \"$B\" \"$C\" \"$D\" \"$E\" \"$F\" \"$G\"",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts sh with the variables from the env file
+        // sh prints each of them within brackets
+        assert
+            .stdout(concat!(
+                "[plain value]\n",
+                "[no#comment]\n",
+                "[]\n",
+                "[escaped \"quotes\" \\ and\nlines]\n",
+                "[single $quoted \\n literal]\n",
+                "[exported with a tab]\n",
+                "[hash # within quotes]\n",
+            ))
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn env_file_reload() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child(".env");
+        tmp_file.write_str("FOO=first\n").unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "--env-file",
+            file,
+            "--",
+            testprog(),
+            "foo",
+            "env",
+            "FOO",
+        ]);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            tmp_file.write_str("FOO=second\n").unwrap();
+        });
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest prints FOO and exits
+        // env file is written
+        // runar restarts runartest with the new value
+        assert
+            .stdout("start foo\nfirst\nend foo\nstart foo\nsecond\nend foo\n")
+            .stderr("");
+    }
+
+    #[test]
+    fn invalid_env_file_fails() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child(".env");
+        tmp_file.write_str("FOO=\"unterminated\n").unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--env-file", file, "--", testprog(), "foo", "success"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        assert.failure();
+    }
+
+    #[test]
+    fn limits() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--rlimit",
+                "nofile=64:128,core=unlimited",
+                "--nice",
+                "5",
+                "--cpu-affinity",
+                "0",
+                "--",
+                testprog(),
+                "foo",
+                "limits",
+            ])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // runar starts runartest with limits, niceness and affinity
+        // runartest prints them
+        assert
+            .stdout("start foo\nnofile 64 128\nnice 5\ncpus 0\nend foo\n")
+            .stderr("")
+            .success();
+    }
+
+    #[test]
+    fn negative_nice() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-e", "--nice", "-5", "--", testprog(), "foo", "limits"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        // Only root may lower the niceness, runar tells so before anything is run
+        if Uid::effective().is_root() {
+            let output = assert.success().get_output().stdout.clone();
+            assert!(String::from_utf8(output).unwrap().contains("\nnice -5\n"));
+        } else {
+            let output = assert.failure().get_output().stderr.clone();
+            let stderr = String::from_utf8(output).unwrap();
+            assert!(stderr.starts_with("<runar> Error: --nice -5 requires root"));
+        }
+    }
+
+    #[test]
+    fn invalid_rlimit_fails() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--rlimit", "files=64", "--", testprog(), "foo", "success"])
+            .timeout(Duration::from_millis(200))
+            .assert();
+
+        assert.failure();
+    }
+
+    #[test]
+    fn procfile() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile
+            .write_str(&format!(
+                "# processes\n\
+                a: -e -- {0} a success\n\
+                bb: -e -- {0} bb success\n",
+                testprog()
+            ))
+            .unwrap();
+
+        let output = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-P", procfile.to_str().unwrap()])
+            .timeout(Duration::from_millis(2000))
+            .output()
+            .unwrap();
+
+        // runar starts a runar for each process, which run runartest once
+        // the output of each process is prefixed with its name
+        // runar exits once all processes have exited
+        let mut lines: Vec<&str> = std::str::from_utf8(&output.stdout)
+            .unwrap()
+            .lines()
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            ["a  | end a", "a  | start a", "bb | end bb", "bb | start bb"]
+        );
+        assert_eq!(output.status.code(), Some(0));
+    }
+
+    #[test]
+    fn procfile_failure_stops_all() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile
+            .write_str(&format!(
+                "sleeper: {0} sleeper sleep\n\
+                failer: -E -- {0} failer error\n",
+                testprog()
+            ))
+            .unwrap();
+
+        let output = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-P", procfile.to_str().unwrap()])
+            .timeout(Duration::from_millis(2000))
+            .output()
+            .unwrap();
+
+        // runartest failer exits with an error and its runar exits
+        // runar stops sleeper and exits with the status of failer
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("failer  | err failer\n"));
+        assert!(stderr.contains("<runar> failer exited with status 13, stopping all processes\n"));
+        assert_eq!(output.status.code(), Some(13));
+    }
+
+    #[test]
+    fn procfile_dependencies() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile
+            .write_str(&format!(
+                "db: --ready file:{0}/ready -- trap 'echo db stopped; exit 0' TERM; \
+                    sleep 0.2; touch {0}/ready; while :; do sleep 0.1; done\n\
+                migrate: -e --after db --ready exit -- echo migrating\n\
+                api: --after migrate -- trap 'sleep 0.3; echo api stopped; exit 0' TERM; \
+                    echo serving; while :; do sleep 0.1; done\n",
+                tmp_dir.path().display()
+            ))
+            .unwrap();
+
+        let runar = run_runar(vec!["-P", procfile.to_str().unwrap()]);
+
+        delayed_sigterm(1000, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // db creates its ready file, then migrate runs and exits, then api starts
+        // runar gets sigterm and stops api before db
+        assert
+            .stdout(
+                "migrate | migrating\n\
+                api     | serving\n\
+                api     | api stopped\n\
+                db      | db stopped\n",
+            )
+            .code(0);
+    }
+
+    #[test]
+    fn procfile_dependency_restart() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile
+            .write_str(&format!(
+                "db: -s --ready file:{0}/ready -- trap 'echo db stopped; exit 0' TERM; \
+                    if [ -e {0}/once ]; then sleep 0.2; echo db again; touch {0}/ready; \
+                    while :; do sleep 0.1; done; fi; \
+                    touch {0}/once {0}/ready; echo db; sleep 0.5; rm {0}/ready\n\
+                api: --after db -- trap 'echo api stopped; exit 0' TERM; \
+                    echo serving; while :; do sleep 0.1; done\n",
+                tmp_dir.path().display()
+            ))
+            .unwrap();
+
+        let runar = run_runar(vec!["-P", procfile.to_str().unwrap()]);
+
+        delayed_sigterm(1500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+        let assert = Assert::new(output);
+
+        // db exits and is restarted, api is stopped and only started again once db is ready
+        assert!(
+            stderr.contains("<runar> db restarted, restarting the processes that come after it\n")
+        );
+        assert
+            .stdout(
+                "db  | db\n\
+                api | serving\n\
+                api | api stopped\n\
+                db  | db again\n\
+                api | serving\n\
+                api | api stopped\n\
+                db  | db stopped\n",
+            )
+            .code(0);
+    }
+
+    #[test]
+    fn procfile_dependency_cycle_fails() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile
+            .write_str("a: --after b -- true\nb: --after a -- true\n")
+            .unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-P", procfile.to_str().unwrap()])
+            .assert();
+
+        assert
+            .stderr(format!(
+                "<runar> Error: {}: dependency cycle between a, b\n",
+                procfile.display()
+            ))
+            .code(1);
+    }
+
+    #[test]
+    fn procfile_with_global_flags_fails() {
+        let tmp_dir = TempDir::new().unwrap();
+        let procfile = tmp_dir.child("Procfile");
+        procfile.write_str("a: true\n").unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["-v", "-P", procfile.to_str().unwrap(), "--timeout", "1s"])
+            .assert();
+
+        assert
+            .stderr(
+                "<runar> Error: --timeout cannot be used with -P, give it to the processes in the Procfile\n",
+            )
+            .code(1);
+    }
+
+    #[test]
+    fn file_watch() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec!["-f", file, "--", testprog(), "foo", "sleep"]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest sleeps
+        // file is written
+        // runar restarts runartest
+        // runartest sleeps
+        // runar gets sigterm
+        // runar sends sigterm to runartest
+        assert.stdout("start foo\nstart foo\n").stderr("");
+    }
+
+    #[test]
+    fn build_gates_restart() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.write_str("good").unwrap();
+        let file = tmp_file.to_str().unwrap();
+        let build = format!("grep -q good {file}");
+
+        let runar = run_runar(vec![
+            "-f",
+            file,
+            "--build",
+            &build,
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            tmp_file.write_str("bad").unwrap();
+            thread::sleep(Duration::from_millis(300));
+            tmp_file.write_str("good").unwrap();
+        });
+        delayed_sigterm(1000, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar builds and starts runartest
+        // file is broken, the build fails and runartest keeps running
+        // file is fixed, the build succeeds and runar restarts runartest
+        assert
+            .stdout("start foo\nstart foo\n")
+            .stderr("<runar> build failed with status 1\n")
+            .code(143);
+    }
+
+    #[test]
+    fn build_daemon_spared() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+        let build = format!(
+            "{} build escape build-daemon sleep >/dev/null 2>&1",
+            testprog()
+        );
+
+        let runar = run_runar(vec![
+            "-f",
+            file,
+            "--build",
+            &build,
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            tmp_file.touch().unwrap();
+        });
+        delayed_sigterm(800, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let daemons = pids_named("build-daemon");
+        for &daemon in &daemons {
+            kill(daemon, Signal::SIGKILL).unwrap();
+        }
+
+        // Each build leaves a daemon behind, which is re-parented to runar
+        // runar restarts runartest and exits, the daemons are not part of its runs
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "start foo\nstart foo\n"
+        );
+        assert_eq!(daemons.len(), 2);
+    }
+
+    #[test]
+    fn file_watch_no_interrupt() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-f",
+            file,
+            "--no-interrupt",
+            "--",
+            testprog(),
+            "foo",
+            "work",
+        ]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(900, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest, which works for 500ms
+        // file is written, runartest is left to finish its work
+        // runar runs runartest again once it is done
+        // runar gets sigterm during the second run
+        assert
+            .stdout("start foo\nend foo\nstart foo\n")
+            .stderr("")
+            .code(143);
+    }
+
+    #[test]
+    fn file_watch_no_interrupt_exit() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap().to_string();
+
+        delayed_write_file(200, tmp_file);
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "-f",
+                &file,
+                "--no-interrupt",
+                "--",
+                testprog(),
+                "foo",
+                "work",
+            ])
+            .timeout(Duration::from_millis(1500))
+            .assert();
+
+        // runar starts runartest, which works for 500ms
+        // file is written, runartest is left to finish its work
+        // runartest exits with 0, and -e takes precedence over running it again
+        assert.stdout("start foo\nend foo\n").stderr("").success();
+    }
+
+    #[test]
+    fn file_watch_postpone() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-f",
+            file,
+            "--postpone",
+            "--",
+            testprog(),
+            "foo",
+            "success",
+        ]);
+
+        delayed_write_file(300, tmp_file);
+        delayed_sigterm(600, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar waits without starting runartest
+        // file is written
+        // runar starts runartest for the first time
+        assert.stdout("start foo\nend foo\n").stderr("");
+    }
+
+    #[test]
+    fn log_file_rotation() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+        let log_file = tmp_dir.child("out.log");
+        let log = log_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-rf",
+            dir,
+            "--log-file",
+            log,
+            "--log-max-size",
+            "25",
+            "--log-keep",
+            "1",
+            "--",
+            "sh",
+            "-c",
+            "echo line one; echo line two; echo line three; sleep 10",
+        ]);
+
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // The output is passed through and written to the log, which is rotated after 25 bytes
+        // The log is in the watched directory, but writing and rotating it does not restart sh
+        assert.stdout("line one\nline two\nline three\n").stderr("");
+        log_file.assert("line three\n");
+        tmp_dir.child("out.log.1").assert("line one\nline two\n");
+        assert!(!tmp_dir.child("out.log.2").exists());
+    }
+
+    #[test]
+    fn log_file_open_fails() {
+        let tmp_dir = TempDir::new().unwrap();
+        let log_dir = tmp_dir.child("logs");
+        log_dir.create_dir_all().unwrap();
+        let log = log_dir.path().canonicalize().unwrap();
+
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args(["--log-file", log.to_str().unwrap(), "--", "true"])
+            .timeout(Duration::from_millis(1000))
+            .assert();
+
+        // A directory can not be opened as the log file, nothing is run
+        assert
+            .stdout("")
+            .stderr(format!(
+                "<runar> Error: {}: Is a directory (os error 21)\n",
+                log.display()
+            ))
+            .code(1);
+    }
+
+    #[test]
+    fn file_watch_clear() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-f",
+            file,
+            "--clear",
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        // The first run starts as usual
+        // file is written and the banner tells why runartest runs again
+        // stdout is a pipe, so the screen is not cleared
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "start foo");
+        assert!(lines[1].starts_with("<runar> run 2 at "));
+        assert!(lines[1].ends_with(", files changed, previous exit status 143"));
+        assert_eq!(lines[2], "start foo");
+
+        let runar = run_runar(vec![
+            "-S",
+            "--timeout",
+            "200ms",
+            "--clear",
+            "--",
+            testprog(),
+            "foo",
+            "sleep",
+        ]);
+
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        // runartest is stopped by --timeout, which the banner tells as the reason
+        assert!(stdout.contains(", timed out, previous exit status 124\n"));
+    }
+
+    #[test]
+    fn recursive_file_watch() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+        let tmp_file = tmp_dir.child("deep/file");
+        tmp_file.touch().unwrap();
+
+        let runar = run_runar(vec!["-rf", dir, "--", testprog(), "foo", "sleep"]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest sleeps
+        // file is written
+        // runar restarts runartest
+        // runartest sleeps
+        // runar gets sigterm
+        // runar sends sigterm to runartest
+        assert.stdout("start foo\nstart foo\n").stderr("");
+    }
+
+    #[test]
+    fn uninterruptible_cmd() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec!["-Ek10", "-f", file, "--", testprog(), "foo", "hang"]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest hangs
+        // file is written
+        // runar attempts to restart runartest
+        // runartest gets sigterm
+        // runartest gets sigkill
+        // runar restarts runartest
+        // runar gets sigterm
+        // runar sends sigterm and then sigkill to runartest
+        assert.stdout("start foo\nstart foo\n").stderr("");
+    }
+
+    #[test]
+    fn multiple_writes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+
+        let tmp_file_1 = tmp_dir.child("file1");
+        tmp_file_1.touch().unwrap();
+
+        let tmp_file_2 = tmp_dir.child("file2");
+        tmp_file_2.touch().unwrap();
+
+        let tmp_file_3 = tmp_dir.child("file3");
+        tmp_file_3.touch().unwrap();
+
+        let runar = run_runar(vec!["-rf", dir, "--", testprog(), "foo", "sleep"]);
+
+        delayed_write_file(200, tmp_file_1);
+        delayed_write_file(230, tmp_file_2);
+        delayed_write_file(260, tmp_file_3);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest
+        // runartest sleeps
+        // file1 is written
+        // runar stops runartest
+        // file2 and file3 are written
+        // runar clears filewrites from buffer
+        // runar starts runartest
+        assert.stdout("start foo\nstart foo\n").stderr("");
+    }
+
+    #[test]
+    fn file_watch_with_child_sleep() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-k10",
+            "-f",
+            file,
+            "--",
+            testprog(),
+            "foo",
+            "waitchild",
+            "bar",
+            "sleep",
+        ]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest foo
+        // foo starts child bar
+        // foo waits on bar
+        // bar sleeps
+        // file is written
+        // runar stops foo
+        // runar cleans up bar
+        // runar restarts foo
+        assert
+            .stdout("start foo\nstart bar\nstart foo\nstart bar\n")
+            .stderr("");
+    }
+
+    #[test]
+    fn file_watch_with_child_hang() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_file = tmp_dir.child("file");
+        tmp_file.touch().unwrap();
+        let file = tmp_file.to_str().unwrap();
+
+        let runar = run_runar(vec![
+            "-k10",
+            "-f",
+            file,
+            "--",
+            testprog(),
+            "foo",
+            "waitchild",
+            "bar",
+            "hang",
+        ]);
+
+        delayed_write_file(200, tmp_file);
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest foo
+        // foo starts child bar
+        // foo waits on bar
+        // bar hangs
+        // file is written
+        // runar stops foo
+        // runar cleans up bar (by killing)
+        // runar restarts foo
+        assert
+            .stdout("start foo\nstart bar\nstart foo\nstart bar\n")
+            .stderr("");
+    }
+
+    #[test]
+    fn grandchild_cleanup() {
+        let runar = run_runar(vec!["-E", "--", testprog(), "foo", "child", "bar", "sleep"]);
+
+        delayed_sigterm(500, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
 
         // runar starts runartest foo
         // foo starts child bar
@@ -406,4 +1907,193 @@ mod integration {
         // runar cleans up bar
         assert.stdout("start foo\nstart bar\nend foo\n").stderr("");
     }
+
+    #[test]
+    fn kill_timer_outlasts_other_exits() {
+        let runar = run_runar(vec![
+            "-k",
+            "2000",
+            "--",
+            testprog(),
+            "foo",
+            "linger",
+            "bar",
+            "child",
+            "lingerer-orphan",
+            "sleep",
+        ]);
+
+        delayed_sigterm(300, runar.id() as i32);
+
+        let output = runar.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        // runar starts runartest foo
+        // foo starts bar, which starts lingerer-orphan and exits, which is re-parented to runar
+        // runar sends sigterm, lingerer-orphan exits at once while foo takes its time
+        // runar keeps waiting for foo until the kill timer runs out
+        assert!(stdout.starts_with("start foo\n"));
+        assert!(stdout.ends_with("end foo\n"));
+        assert_eq!(processes_named("lingerer-orphan"), 0);
+    }
+
+    #[test]
+    fn output_read_while_stopping() {
+        let runar = run_runar(vec![
+            "--mark-stderr",
+            "--",
+            "sh",
+            "-c",
+            "trap 'yes | head -c 200000; exit 0' TERM; while :; do sleep 0.1; done",
+        ]);
+
+        delayed_sigterm(300, runar.id() as i32);
+
+        let start = Instant::now();
+        let output = runar.wait_with_output().unwrap();
+
+        // sh writes more than a pipe holds once it gets sigterm
+        // runar reads it while waiting, so sh exits well before the kill timer runs out
+        assert_eq!(output.stdout, b"y\n".repeat(100_000));
+        assert!(start.elapsed() < Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn coalesced_orphans_reaped() {
+        let runar = run_runar(vec!["--", testprog(), "foo", "orphans", "bar"]);
+        let pid = runar.id();
+
+        thread::sleep(Duration::from_millis(300));
+        let zombies = zombie_children(pid);
+        kill(Pid::from_raw(pid as i32), Signal::SIGTERM).unwrap();
+
+        let output = runar.wait_with_output().unwrap();
+        let assert = Assert::new(output);
+
+        // runar starts runartest foo
+        // foo starts five children bar and exits
+        // all bars exit at the same time, their SIGCHLDs are merged
+        // runar reaps every bar
+        assert.stderr("");
+        assert_eq!(zombies, 0);
+    }
+
+    #[test]
+    fn child_exit_among_orphans() {
+        // The child may be reaped along with its orphans before its pidfd reports the exit
+        for _ in 0..10 {
+            let assert = Command::cargo_bin("runar")
+                .unwrap()
+                .args(["-E", "--", testprog(), "foo", "orphanerror", "bar"])
+                .timeout(Duration::from_millis(1000))
+                .assert();
+
+            assert.code(13).stdout("start foo\n").stderr("err foo\n");
+        }
+    }
+
+    #[test]
+    fn escaped_grandchild_cleanup() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "--",
+                testprog(),
+                "foo",
+                "escape",
+                "escapee-term",
+                "sleep",
+            ])
+            .timeout(Duration::from_millis(1000))
+            .assert();
+
+        let escapees = processes_named("escapee-term");
+
+        // runar starts runartest foo
+        // foo starts child escapee in a new process group
+        // foo exits
+        // runar finds escapee in its process tree and sends it sigterm
+        assert
+            .stdout("start foo\nstart escapee-term\nend foo\n")
+            .stderr("")
+            .success();
+        assert_eq!(escapees, 0);
+    }
+
+    #[test]
+    fn escaped_grandchild_hang() {
+        let assert = Command::cargo_bin("runar")
+            .unwrap()
+            .args([
+                "-e",
+                "-k10",
+                "--",
+                testprog(),
+                "foo",
+                "escape",
+                "escapee-hang",
+                "hang",
+            ])
+            .timeout(Duration::from_millis(1000))
+            .assert();
+
+        let escapees = processes_named("escapee-hang");
+
+        // runar starts runartest foo
+        // foo starts child escapee in a new process group
+        // foo exits
+        // runar finds escapee in its process tree and sends it sigterm
+        // escapee hangs and gets sigkill
+        assert
+            .stdout("start foo\nstart escapee-hang\nend foo\n")
+            .stderr("")
+            .success();
+        assert_eq!(escapees, 0);
+    }
+
+    #[test]
+    #[ignore = "requires a writable cgroup v2 hierarchy, run with --ignored"]
+    fn cgroup_cleanup() {
+        let cgroup = test_cgroup("runar-test-cgroup").expect("cgroup v2 is not available");
+
+        // Without a kill timer the processes get SIGKILL at once, runar still waits for them
+        for kill_timer in ["5000", "0"] {
+            let assert = Command::cargo_bin("runar")
+                .unwrap()
+                .args([
+                    "-e",
+                    "-k",
+                    kill_timer,
+                    "--cgroup",
+                    cgroup.to_str().unwrap(),
+                    "--",
+                    testprog(),
+                    "foo",
+                    "escape",
+                    "escapee-cgroup",
+                    "sleep",
+                ])
+                .timeout(Duration::from_millis(1000))
+                .assert();
+
+            let escapees = processes_named("escapee-cgroup");
+            let leftovers = fs::read_dir(&cgroup).unwrap().flatten();
+            let leftovers = leftovers.filter(|entry| entry.path().is_dir()).count();
+
+            // runar starts runartest foo in a new cgroup
+            // foo starts child escapee in a new process group
+            // foo exits
+            // runar kills escapee through the cgroup
+            // runar removes the cgroup and exits
+            assert
+                .stdout("start foo\nstart escapee-cgroup\nend foo\n")
+                .stderr("")
+                .success();
+            assert_eq!(escapees, 0);
+            assert_eq!(leftovers, 0);
+        }
+
+        fs::remove_dir(&cgroup).unwrap();
+    }
 }